@@ -0,0 +1,219 @@
+use crate::error::{Error, Result};
+use crate::twitter::{Config, SearchOptions, TwitterAccount};
+use futures::TryStreamExt;
+use std::path::Path;
+
+/// Lists return at most this many members per call
+const N_LIST_MEMBERS_PER_PAGE: i32 = 5000;
+
+/// One row of an imported CSV file, the category falls back to `--category`
+#[derive(serde::Deserialize, Debug)]
+struct AccountRow {
+    handle: String,
+    #[serde(default)]
+    category: Option<String>,
+}
+
+/// "twitter" and "@twitter" both become "@twitter"
+fn normalize_handle(handle: &str) -> String {
+    format!("@{}", handle.trim().trim_start_matches('@'))
+}
+
+impl Config {
+    /// Index of `handle`, ignoring case like Twitter does
+    fn position_of(&self, handle: &str) -> Option<usize> {
+        let handle = normalize_handle(handle).to_lowercase();
+        self.accounts
+            .iter()
+            .position(|account| normalize_handle(&account.handle).to_lowercase() == handle)
+    }
+
+    /// Track `handle` in `category`, which has to be declared already
+    pub fn add_account(&mut self, handle: &str, category: &str) -> Result<()> {
+        if !self.categories.iter().any(|known| known == category) {
+            return Err(Error::Usage(format!(
+                "Category {:?} is not declared, categories are {:?}",
+                category, self.categories
+            )));
+        }
+        if let Some(i) = self.position_of(handle) {
+            return Err(Error::Usage(format!(
+                "{} is already tracked in category {:?}",
+                self.accounts[i].handle, self.accounts[i].category
+            )));
+        }
+        self.accounts.push(TwitterAccount {
+            handle: normalize_handle(handle),
+            category: category.to_string(),
+            timeline: false,
+            search: SearchOptions::default(),
+            extra: serde_json::Map::new(),
+        });
+        Ok(())
+    }
+
+    pub fn remove_account(&mut self, handle: &str) -> Result<TwitterAccount> {
+        match self.position_of(handle) {
+            Some(i) => Ok(self.accounts.remove(i)),
+            None => Err(Error::Usage(format!("{} is not tracked", handle))),
+        }
+    }
+
+    /// Add every handle that isn't tracked yet, returns how many were added
+    fn add_new_accounts(&mut self, accounts: Vec<(String, String)>) -> Result<usize> {
+        let mut n_added = 0;
+        for (handle, category) in accounts {
+            if self.position_of(&handle).is_some() {
+                println!(
+                    "Skipping {}, it's already tracked",
+                    normalize_handle(&handle)
+                );
+                continue;
+            }
+            self.add_account(&handle, &category)?;
+            n_added += 1;
+        }
+        Ok(n_added)
+    }
+
+    /// Import a CSV file with a `handle` and an optional `category` column
+    pub fn import_csv(&mut self, csv_path: &Path, category: Option<&str>) -> Result<usize> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(csv_path)
+            .map_err(|e| Error::Parse(format!("Could not read {:?}: {}", csv_path, e)))?;
+        let mut accounts = Vec::new();
+        for (i, row) in reader.deserialize::<AccountRow>().enumerate() {
+            let row =
+                row.map_err(|e| Error::Parse(format!("Could not parse {:?}: {}", csv_path, e)))?;
+            let category = row
+                .category
+                .filter(|category| !category.is_empty())
+                .or_else(|| category.map(String::from))
+                .ok_or_else(|| {
+                    Error::Usage(format!(
+                        "Row {} of {:?} has no category and no --category was given",
+                        i + 1,
+                        csv_path
+                    ))
+                })?;
+            accounts.push((row.handle, category));
+        }
+        self.add_new_accounts(accounts)
+    }
+
+    /// Import the members of a Twitter list, given as "owner/slug" or its numeric id
+    pub async fn import_list(
+        &mut self,
+        list: &str,
+        category: &str,
+        token: &egg_mode::Token,
+    ) -> Result<usize> {
+        let list_id = match (list.parse::<u64>(), list.split_once('/')) {
+            (Ok(id), _) => egg_mode::list::ListID::from_id(id),
+            (_, Some((owner, slug))) => egg_mode::list::ListID::from_slug(
+                owner.trim_start_matches('@').to_string(),
+                slug.to_string(),
+            ),
+            _ => {
+                return Err(Error::Usage(format!(
+                    "List {:?} should be \"owner/slug\" or a list id",
+                    list
+                )))
+            }
+        };
+        let members: Vec<_> = egg_mode::list::members(list_id, token)
+            .with_page_size(N_LIST_MEMBERS_PER_PAGE)
+            .try_collect()
+            .await?;
+        println!("Found {} members in list {}", members.len(), list);
+        self.add_new_accounts(
+            members
+                .into_iter()
+                .map(|member| (member.response.screen_name, category.to_string()))
+                .collect(),
+        )
+    }
+}
+
+/// Print tracked accounts, only the ones in `categories` if there are any
+pub fn list_accounts(config: &Config, categories: &[String]) -> Result<()> {
+    for account in config.accounts_in(categories)? {
+        println!("{}\t{}", account.handle, account.category);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::twitter::Config;
+    use crate::util::test::{get_test_config, get_test_dir};
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn test_add_remove_account() {
+        let mut config = get_test_config();
+        config.add_account("nytimes", "news").unwrap();
+        assert_eq!(config.accounts[2].handle, "@nytimes");
+        // Handles are case insensitive and categories have to be declared
+        assert!(config.add_account("@NYTIMES", "news").is_err());
+        assert!(config.add_account("@guardian", "newspapers").is_err());
+
+        let removed = config.remove_account("@nyTIMES").unwrap();
+        assert_eq!(removed.handle, "@nytimes");
+        assert_eq!(config.accounts.len(), 2);
+        assert!(config.remove_account("@nytimes").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_import_csv() {
+        let test_dir = get_test_dir("test_import_csv");
+        let csv_path = test_dir.join("accounts.csv");
+        std::fs::write(
+            &csv_path,
+            "handle,category\n@BBCWorld,news\nnytimes,\n@Twitter,test\n@guardian,\n",
+        )
+        .unwrap();
+
+        let mut config = get_test_config();
+        let n_added = config.import_csv(&csv_path, Some("news")).unwrap();
+        assert_eq!(n_added, 2);
+        assert_eq!(config.accounts[2].handle, "@nytimes");
+        assert_eq!(config.accounts[2].category, "news");
+    }
+
+    #[tokio::test]
+    // Saving an unchanged configuration leaves the file as it is
+    async fn test_save_keeps_formatting() {
+        let config_path = Path::new("conf/accounts.json");
+        let config = Config::get(config_path).unwrap();
+        let test_dir = get_test_dir("test_save_keeps_formatting");
+        let saved_path = test_dir.join("accounts.json");
+        config.save(&saved_path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(saved_path).unwrap(),
+            std::fs::read_to_string(config_path).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    // Keys added by hand or by newer versions survive adding an account
+    async fn test_save_keeps_unknown_keys() {
+        let mut config: Config = serde_json::from_str(
+            r#"{"categories": ["test"], "comment": "hand written", "accounts": [{"handle": "@twitter", "category": "test", "lang": "en", "note": "keep me"}]}"#,
+        )
+        .unwrap();
+        config.add_account("@BBCWorld", "test").unwrap();
+        let test_dir = get_test_dir("test_save_keeps_unknown_keys");
+        let saved_path = test_dir.join("accounts.json");
+        config.save(&saved_path).unwrap();
+
+        let saved: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(saved_path).unwrap()).unwrap();
+        assert_eq!(saved["comment"], "hand written");
+        assert_eq!(saved["accounts"][0]["note"], "keep me");
+        assert_eq!(saved["accounts"][0]["lang"], "en");
+        assert!(saved["accounts"][0].get("extra").is_none());
+        assert_eq!(saved["accounts"][1]["handle"], "@BBCWorld");
+    }
+}
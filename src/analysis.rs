@@ -1,81 +1,346 @@
 extern crate chrono;
 extern crate regex;
-use crate::{storage::StorageHandler, twitter::QueryResult};
+use crate::error::{Error, Result};
+use crate::{
+    storage::{QueryMatch, Storage},
+    twitter::{Author, Config, QueryKind, QueryResult},
+};
+use chrono::{DateTime, Utc};
 use regex::RegexSet;
-use std::{collections::BTreeMap, io, iter::FromIterator, path::Path};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    iter::FromIterator,
+};
 
 /// Result of examining account
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct SearchAnalysis {
     pub queries: Vec<String>,
+    /// Set when the queries are the accounts of one category
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    /// Analyses stored before timelines were fetched only ever covered searches
+    #[serde(default)]
+    pub kind: QueryKind,
     pub date_utc: chrono::DateTime<chrono::Utc>,
     pub word_frequency: Vec<(String, usize)>,
     pub handle_patterns: Vec<(HandlePattern, usize)>,
+    /// Unique authors per account age, youngest accounts first
+    #[serde(default)]
+    pub account_ages: Vec<(AccountAge, usize)>,
+    /// Unique authors per profile location, most common first
+    #[serde(default)]
+    pub locations: Vec<(String, usize)>,
+    /// Retweets among the analyzed tweets, before `retweets` was applied
+    #[serde(default)]
+    pub retweet_count: usize,
+    #[serde(default)]
+    pub retweets: RetweetMode,
+    /// Period the tweets were posted in, all of them if unbounded
+    #[serde(default, skip_serializing_if = "TimeWindow::is_unbounded")]
+    pub window: TimeWindow,
+    /// Copies of tweets fetched more than once, left out of the analysis
+    #[serde(default)]
+    pub duplicate_count: usize,
 }
 
+/// Words an account uses compared to the words used by people mentioning it
+#[derive(Debug, Clone, PartialEq)]
+pub struct VocabularyComparison {
+    pub handle: String,
+    /// Common in both the account's own tweets and the ones mentioning it
+    pub shared: Vec<String>,
+    /// Common in the account's own tweets only
+    pub only_own: Vec<String>,
+    /// Common in the tweets mentioning the account only
+    pub only_mentions: Vec<String>,
+}
+
+/// The only `AnalysisConfig.version` there is so far
+pub const SUPPORTED_ANALYSIS_CONFIG_VERSION: u32 = 1;
+
 /// Analysis configuration
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct AnalysisConfig {
     pub version: u32,
     pub description: String,
     pub ignored_words: Vec<String>,
+    /// What to do with retweets, counted like any other tweet by default
+    #[serde(default)]
+    pub retweets: RetweetMode,
 }
 
-impl AnalysisConfig {
-    pub fn new(config_path: &std::path::Path) -> Option<AnalysisConfig> {
-        // TODO Map the io::Result or serde_json::Result to the same type
-        let file_string = std::fs::read_to_string(config_path);
-        if file_string.is_err() {
-            eprintln!(
-                "Could not read {:?}, got error:{:?}",
-                config_path,
-                file_string.err()
-            );
-            return None;
+/// How retweets are treated in an analysis
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RetweetMode {
+    /// Every retweet counts as a tweet of its own
+    #[default]
+    Count,
+    /// Retweets of the same status only count once
+    Collapse,
+    /// Retweets are left out entirely
+    Exclude,
+}
+
+impl std::fmt::Display for RetweetMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RetweetMode::Count => write!(f, "counted"),
+            RetweetMode::Collapse => write!(f, "collapsed"),
+            RetweetMode::Exclude => write!(f, "excluded"),
         }
-        let deserialied_json: serde_json::Result<AnalysisConfig> =
-            serde_json::from_str(file_string.unwrap().as_str());
-        if deserialied_json.is_err() {
-            eprintln!("serde_json parse error: {:?}", deserialied_json.err());
-            return None;
+    }
+}
+
+/// Period an analysis covers, open ended on either side if unset
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TimeWindow {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<DateTime<Utc>>,
+    /// Exclusive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<DateTime<Utc>>,
+}
+
+/// "2020-12-01" (midnight UTC) or a full RFC 3339 date and time
+fn parse_date_arg(date: &str) -> Result<DateTime<Utc>> {
+    if let Ok(day) = chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        return Ok(day.and_time(chrono::NaiveTime::MIN).and_utc());
+    }
+    DateTime::parse_from_rfc3339(date)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|_| {
+            Error::Usage(format!(
+                "Invalid date {:?}, expected 2020-12-01 or 2020-12-01T13:37:00Z",
+                date
+            ))
+        })
+}
+
+/// "30m", "12h", "7d" or "2w"
+fn parse_duration_arg(duration: &str) -> Result<chrono::Duration> {
+    let invalid = || {
+        Error::Usage(format!(
+            "Invalid duration {:?}, expected a number followed by m, h, d or w like 7d",
+            duration
+        ))
+    };
+    let unit_start = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let amount: i64 = duration[..unit_start].parse().map_err(|_| invalid())?;
+    let parsed = match &duration[unit_start..] {
+        "m" => chrono::Duration::try_minutes(amount),
+        "h" => chrono::Duration::try_hours(amount),
+        "d" => chrono::Duration::try_days(amount),
+        "w" => chrono::Duration::try_weeks(amount),
+        _ => return Err(invalid()),
+    };
+    parsed.ok_or_else(|| too_far_back(duration))
+}
+
+/// For durations that don't fit in a date
+fn too_far_back(duration: &str) -> Error {
+    Error::Usage(format!("Duration {:?} reaches too far back", duration))
+}
+
+impl TimeWindow {
+    /// Window from `--since`, `--until` and `--last`, the latter counting back from `now`
+    pub fn from_args(
+        since: Option<&str>,
+        until: Option<&str>,
+        last: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Result<TimeWindow> {
+        let window = TimeWindow {
+            since: match (since, last) {
+                (Some(_), Some(_)) => {
+                    return Err(Error::Usage(
+                        "Use either --since or --last, not both".to_string(),
+                    ))
+                }
+                (Some(since), None) => Some(parse_date_arg(since)?),
+                (None, Some(last)) => Some(
+                    now.checked_sub_signed(parse_duration_arg(last)?)
+                        .ok_or_else(|| too_far_back(last))?,
+                ),
+                (None, None) => None,
+            },
+            until: until.map(parse_date_arg).transpose()?,
+        };
+        if let (Some(since), Some(until)) = (window.since, window.until) {
+            if since >= until {
+                return Err(Error::Usage(format!(
+                    "The window starts at {} but ends before that at {}",
+                    since, until
+                )));
+            }
+        }
+        Ok(window)
+    }
+
+    pub fn is_unbounded(&self) -> bool {
+        self.since.is_none() && self.until.is_none()
+    }
+
+    pub fn contains(&self, date: DateTime<Utc>) -> bool {
+        self.since.is_none_or(|since| date >= since) && self.until.is_none_or(|until| date < until)
+    }
+
+    /// Only the tweets posted during the window. Results fetched before it started can't hold
+    /// any of them and are skipped without looking at their tweets, results left without
+    /// tweets are dropped and so are authors left without tweets
+    pub fn apply(&self, query_results: Vec<QueryResult>) -> Vec<QueryResult> {
+        if self.is_unbounded() {
+            return query_results;
         }
+        query_results
+            .into_iter()
+            .filter(|result| self.since.is_none_or(|since| result.date_utc >= since))
+            .map(|mut result| {
+                result.tweets.retain(|tweet| self.contains(tweet.date_utc));
+                result.prune_authors();
+                result
+            })
+            .filter(|result| !result.tweets.is_empty())
+            .collect()
+    }
+}
+
+impl std::fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.since, self.until) {
+            (Some(since), Some(until)) => write!(f, "from {} until {}", since, until),
+            (Some(since), None) => write!(f, "since {}", since),
+            (None, Some(until)) => write!(f, "until {}", until),
+            (None, None) => write!(f, "all time"),
+        }
+    }
+}
 
-        Some(deserialied_json.unwrap())
+impl AnalysisConfig {
+    pub fn new(config_path: &std::path::Path) -> Result<AnalysisConfig> {
+        let file_string = std::fs::read_to_string(config_path)
+            .map_err(|e| Error::Config(format!("Could not read {:?}: {}", config_path, e)))?;
+        serde_json::from_str(file_string.as_str())
+            .map_err(|e| Error::Config(format!("Could not parse {:?}: {}", config_path, e)))
     }
 }
 
 const N_MOST_COMMON_WORDS: usize = 5;
 const N_MOST_HANDLE_PATTERNS: usize = 3;
+const N_MOST_COMMON_LOCATIONS: usize = 5;
+/// How many of the most common words of each side are compared
+const N_COMPARED_WORDS: usize = 20;
 
 impl SearchAnalysis {
+    /// Analyze the stored results of `query`, or of every query `matching` accepts for it
     pub fn from_stored_query(
-        base_dir: &Path,
+        storage: &dyn Storage,
         words_to_ignore: &[String],
         query: &str,
-    ) -> io::Result<SearchAnalysis> {
-        let query_results = StorageHandler::new()
-            .storage_dir(&base_dir)
-            .retrieve_query_results_for(query)?;
-        Ok(SearchAnalysis {
-            queries: query_results.iter().map(|x| x.query.to_string()).collect(),
-            date_utc: chrono::Utc::now(),
-            word_frequency: get_most_common_words(&query_results, &words_to_ignore),
-            handle_patterns: get_most_common_handle_patterns(&query_results),
-        })
+        matching: QueryMatch,
+        kind: QueryKind,
+        retweets: RetweetMode,
+        window: TimeWindow,
+    ) -> Result<SearchAnalysis> {
+        let query_results = window.apply(storage.retrieve_query_results_matching(query, matching)?);
+        SearchAnalysis::from_query_results(query_results, words_to_ignore, kind, retweets, window)
+            .ok_or_else(|| {
+                Error::NoData(format!(
+                    "No stored {} query results for {:?} {}",
+                    kind, query, window
+                ))
+            })
     }
 
     pub fn from_stored_queries(
-        base_dir: &Path,
+        storage: &dyn Storage,
+        words_to_ignore: &[String],
+        kind: QueryKind,
+        retweets: RetweetMode,
+        window: TimeWindow,
+    ) -> Result<SearchAnalysis> {
+        let query_results = window.apply(storage.retrieve_all_query_results()?);
+        SearchAnalysis::from_query_results(query_results, words_to_ignore, kind, retweets, window)
+            .ok_or_else(|| Error::NoData(format!("No stored {} query results {}", kind, window)))
+    }
+
+    /// Analyze the stored results for every account in `category` together
+    pub fn from_stored_category(
+        storage: &dyn Storage,
+        words_to_ignore: &[String],
+        category: &str,
+        accounts: &Config,
+        kind: QueryKind,
+        retweets: RetweetMode,
+        window: TimeWindow,
+    ) -> Result<SearchAnalysis> {
+        let mut query_results: Vec<QueryResult> = Vec::new();
+        for account in accounts.accounts_in(&[category.to_string()])? {
+            query_results.extend(storage.retrieve_query_results_for(&account.handle)?);
+        }
+        let query_results = window.apply(query_results);
+        let mut analysis = SearchAnalysis::from_query_results(
+            query_results,
+            words_to_ignore,
+            kind,
+            retweets,
+            window,
+        )
+        .ok_or_else(|| {
+            Error::NoData(format!(
+                "No stored {} query results for category {:?} {}",
+                kind, category, window
+            ))
+        })?;
+        analysis.category = Some(category.to_string());
+        Ok(analysis)
+    }
+
+    /// Analyze the results of the given kind, None if there aren't any
+    fn from_query_results(
+        query_results: Vec<QueryResult>,
         words_to_ignore: &[String],
-    ) -> io::Result<SearchAnalysis> {
-        let query_results = StorageHandler::new()
-            .storage_dir(&base_dir)
-            .retrieve_all_query_results()?;
-        Ok(SearchAnalysis {
-            queries: query_results.iter().map(|x| x.query.to_string()).collect(),
-            date_utc: chrono::Utc::now(),
-            word_frequency: get_most_common_words(&query_results, &words_to_ignore),
+        kind: QueryKind,
+        retweets: RetweetMode,
+        window: TimeWindow,
+    ) -> Option<SearchAnalysis> {
+        let query_results: Vec<QueryResult> = query_results
+            .into_iter()
+            .filter(|result| result.kind == kind)
+            .collect();
+        if query_results.is_empty() {
+            return None;
+        }
+        let (query_results, duplicate_count) = dedupe_tweets(query_results);
+        let retweet_count = query_results
+            .iter()
+            .flat_map(|result| result.tweets.iter())
+            .filter(|tweet| tweet.is_retweet())
+            .count();
+        let query_results = apply_retweet_mode(query_results, retweets);
+        let date_utc = chrono::Utc::now();
+        Some(SearchAnalysis {
+            // Once per query however often it was polled, analyses are stored under these
+            queries: query_results
+                .iter()
+                .map(|x| x.query.to_string())
+                .collect::<BTreeSet<String>>()
+                .into_iter()
+                .collect(),
+            category: None,
+            kind,
+            date_utc,
+            word_frequency: get_most_common_words(&query_results, words_to_ignore),
             handle_patterns: get_most_common_handle_patterns(&query_results),
+            account_ages: get_account_age_histogram(&query_results, date_utc),
+            locations: get_most_common_locations(&query_results),
+            retweet_count,
+            retweets,
+            window,
+            duplicate_count,
         })
     }
 
@@ -87,7 +352,16 @@ impl SearchAnalysis {
     pub fn summary(&self) -> String {
         let mut summary = String::from("------------------------------------\n");
 
-        summary.push_str(format!("Most common words for queries: {:?}\n", self.queries).as_str());
+        if let Some(category) = &self.category {
+            summary.push_str(format!("Category {}\n", category).as_str());
+        }
+        summary.push_str(
+            format!(
+                "Most common words for {} queries: {:?}\n",
+                self.kind, self.queries
+            )
+            .as_str(),
+        );
 
         for word in self.word_frequency.iter().take(N_MOST_COMMON_WORDS) {
             summary.push_str(format!("{} was seen {} times\n", word.0, word.1).as_str());
@@ -98,19 +372,101 @@ impl SearchAnalysis {
                 format!("The pattern {:?} was seen {} times\n", pattern.0, pattern.1).as_str(),
             );
         }
+
+        for (age, count) in &self.account_ages {
+            summary.push_str(format!("{} authors have accounts {}\n", count, age).as_str());
+        }
+
+        for location in self.locations.iter().take(N_MOST_COMMON_LOCATIONS) {
+            summary.push_str(
+                format!("{} authors are located in {}\n", location.1, location.0).as_str(),
+            );
+        }
+        if !self.window.is_unbounded() {
+            summary.push_str(format!("Covering tweets posted {}\n", self.window).as_str());
+        }
+        if self.duplicate_count > 0 {
+            summary.push_str(
+                format!("{} duplicate tweets were removed\n", self.duplicate_count).as_str(),
+            );
+        }
+        if self.retweet_count > 0 {
+            summary.push_str(
+                format!("{} retweets were {}\n", self.retweet_count, self.retweets).as_str(),
+            );
+        }
         summary.push_str("------------------------------------\n");
 
         summary
     }
 }
 
+impl VocabularyComparison {
+    /// Compare the most common words of an account's timeline with the ones of searches for it
+    pub fn new(handle: &str, timeline: &SearchAnalysis, search: &SearchAnalysis) -> Self {
+        let top_words = |analysis: &SearchAnalysis| -> Vec<String> {
+            analysis
+                .word_frequency
+                .iter()
+                .take(N_COMPARED_WORDS)
+                .map(|(word, _)| word.clone())
+                .collect()
+        };
+        let own = top_words(timeline);
+        let mentions = top_words(search);
+        VocabularyComparison {
+            handle: handle.to_string(),
+            shared: own
+                .iter()
+                .filter(|word| mentions.contains(word))
+                .cloned()
+                .collect(),
+            only_own: own
+                .iter()
+                .filter(|word| !mentions.contains(word))
+                .cloned()
+                .collect(),
+            only_mentions: mentions
+                .iter()
+                .filter(|word| !own.contains(word))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let mut summary = String::from("------------------------------------\n");
+        summary
+            .push_str(format!("Vocabulary of {} compared to its mentions\n", self.handle).as_str());
+        summary.push_str(format!("Used by both: {}\n", self.shared.join(", ")).as_str());
+        summary.push_str(
+            format!(
+                "Only used by {}: {}\n",
+                self.handle,
+                self.only_own.join(", ")
+            )
+            .as_str(),
+        );
+        summary
+            .push_str(format!("Only used by others: {}\n", self.only_mentions.join(", ")).as_str());
+        summary.push_str("------------------------------------\n");
+        summary
+    }
+}
+
 pub async fn run_analysis_with_config(
     config: AnalysisConfig,
-    storage_dir: &Path,
-) -> io::Result<()> {
-    let analysis =
-        SearchAnalysis::from_stored_queries(&Path::new(storage_dir), &config.ignored_words)?;
-    let storage = StorageHandler::new().storage_dir(storage_dir);
+    storage: &dyn Storage,
+    kind: QueryKind,
+    window: TimeWindow,
+) -> Result<()> {
+    let analysis = SearchAnalysis::from_stored_queries(
+        storage,
+        &config.ignored_words,
+        kind,
+        config.retweets,
+        window,
+    )?;
     storage.save_analysis(&analysis)?;
     println!("{}", analysis.summary());
     Ok(())
@@ -118,16 +474,101 @@ pub async fn run_analysis_with_config(
 
 pub async fn run_analysis_on_query(
     config: AnalysisConfig,
-    storage_dir: &Path,
+    storage: &dyn Storage,
     query_to_analyze: &str,
-) -> io::Result<()> {
-    let analysis =
-        SearchAnalysis::from_stored_query(storage_dir, &config.ignored_words, query_to_analyze)?;
-    let storage = StorageHandler::new().storage_dir(storage_dir);
+    matching: QueryMatch,
+    kind: QueryKind,
+    window: TimeWindow,
+) -> Result<()> {
+    let analysis = SearchAnalysis::from_stored_query(
+        storage,
+        &config.ignored_words,
+        query_to_analyze,
+        matching,
+        kind,
+        config.retweets,
+        window,
+    )?;
     storage.save_analysis(&analysis)?;
     println!("{}", analysis.summary());
     Ok(())
 }
+
+/// One analysis per category, each over the stored results of all of its accounts
+pub async fn run_analysis_on_categories(
+    config: AnalysisConfig,
+    accounts: &Config,
+    storage: &dyn Storage,
+    categories: &[String],
+    kind: QueryKind,
+    window: TimeWindow,
+) -> Result<()> {
+    for category in categories {
+        let analysis = SearchAnalysis::from_stored_category(
+            storage,
+            &config.ignored_words,
+            category,
+            accounts,
+            kind,
+            config.retweets,
+            window,
+        )?;
+        storage.save_analysis(&analysis)?;
+        println!("{}", analysis.summary());
+    }
+    Ok(())
+}
+
+/// Print when each stored analysis was made and what it covered, oldest first
+pub fn list_analyses(storage: &dyn Storage) -> Result<()> {
+    let mut analyses = storage.retrieve_analyses()?;
+    analyses.sort_by_key(|analysis| analysis.date_utc);
+    for analysis in analyses {
+        let covered = match &analysis.category {
+            Some(category) => format!("category {}", category),
+            None => analysis.queries.join(", "),
+        };
+        println!(
+            "{}\t{}\t{}\t{}",
+            analysis.date_utc, analysis.kind, covered, analysis.window
+        );
+    }
+    Ok(())
+}
+
+/// Analyze both the timeline of `handle` and searches for it, then compare their vocabulary
+pub async fn run_vocabulary_comparison(
+    config: AnalysisConfig,
+    storage: &dyn Storage,
+    handle: &str,
+    window: TimeWindow,
+) -> Result<()> {
+    let timeline = SearchAnalysis::from_stored_query(
+        storage,
+        &config.ignored_words,
+        handle,
+        QueryMatch::Exact,
+        QueryKind::Timeline,
+        config.retweets,
+        window,
+    )?;
+    let search = SearchAnalysis::from_stored_query(
+        storage,
+        &config.ignored_words,
+        handle,
+        QueryMatch::Exact,
+        QueryKind::Search,
+        config.retweets,
+        window,
+    )?;
+    storage.save_analysis(&timeline)?;
+    storage.save_analysis(&search)?;
+    println!(
+        "{}",
+        VocabularyComparison::new(handle, &timeline, &search).summary()
+    );
+    Ok(())
+}
 /**
  *  A category of handle format with their corresponding regex
  *
@@ -144,7 +585,7 @@ pub async fn run_analysis_on_query(
  *      - Account age
  *      - Account location
  */
-/// TODO: Is it possible to map an enum directly to a Regex?
+// TODO: Is it possible to map an enum directly to a Regex?
 #[derive(serde::Serialize, serde::Deserialize, Debug, Eq, PartialEq, PartialOrd, Ord, Clone)]
 pub enum HandlePattern {
     NameWithNumbers = 0, // somename1234514 [a-z]+\d+
@@ -158,7 +599,7 @@ pub enum HandlePattern {
 impl HandlePattern {
     /// Parse a handle into a category
     pub fn from(handle: &str) -> HandlePattern {
-        let set = RegexSet::new(&[
+        let set = RegexSet::new([
             r"^[a-z]+\d+$",              // NameWithNumbers
             r"^[a-z]+$",                 // Lowercase
             r"^[a-z]+[A-Z][a-z]+$",      // pascalCase
@@ -185,6 +626,150 @@ impl HandlePattern {
     }
 }
 
+/// How long ago an author created their account, relative to the analysis
+#[derive(serde::Serialize, serde::Deserialize, Debug, Eq, PartialEq, PartialOrd, Ord, Clone)]
+pub enum AccountAge {
+    LessThanAMonth = 0,
+    LessThanAYear = 1,
+    OneToTwoYears = 2,
+    TwoToFiveYears = 3,
+    FiveToTenYears = 4,
+    OverTenYears = 5,
+}
+
+impl AccountAge {
+    /// Bucket an account created at `created_at` as seen from `now`
+    pub fn from(
+        created_at: chrono::DateTime<chrono::Utc>,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> AccountAge {
+        let days = (now - created_at).num_days();
+        if days < 30 {
+            AccountAge::LessThanAMonth
+        } else if days < 365 {
+            AccountAge::LessThanAYear
+        } else if days < 2 * 365 {
+            AccountAge::OneToTwoYears
+        } else if days < 5 * 365 {
+            AccountAge::TwoToFiveYears
+        } else if days < 10 * 365 {
+            AccountAge::FiveToTenYears
+        } else {
+            AccountAge::OverTenYears
+        }
+    }
+}
+
+impl std::fmt::Display for AccountAge {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AccountAge::LessThanAMonth => write!(f, "younger than a month"),
+            AccountAge::LessThanAYear => write!(f, "younger than a year"),
+            AccountAge::OneToTwoYears => write!(f, "one to two years old"),
+            AccountAge::TwoToFiveYears => write!(f, "two to five years old"),
+            AccountAge::FiveToTenYears => write!(f, "five to ten years old"),
+            AccountAge::OverTenYears => write!(f, "over ten years old"),
+        }
+    }
+}
+
+/// Every author across the results once, the most recently fetched profile wins
+fn get_unique_authors(query_results: &[QueryResult]) -> BTreeMap<&String, &Author> {
+    let mut sorted_results: Vec<&QueryResult> = query_results.iter().collect();
+    sorted_results.sort_by_key(|result| result.date_utc);
+    sorted_results
+        .into_iter()
+        .flat_map(|result| result.authors.iter())
+        .collect()
+}
+
+/// Counts unique authors per account age, ordered from youngest to oldest
+pub fn get_account_age_histogram(
+    query_results: &[QueryResult],
+    now: chrono::DateTime<chrono::Utc>,
+) -> Vec<(AccountAge, usize)> {
+    let mut map_age_to_count: BTreeMap<AccountAge, usize> = BTreeMap::new();
+    for author in get_unique_authors(query_results).values() {
+        *map_age_to_count
+            .entry(AccountAge::from(author.created_at, now))
+            .or_insert(0) += 1;
+    }
+    Vec::from_iter(map_age_to_count)
+}
+
+/// Finds the most common profile locations of unique authors
+pub fn get_most_common_locations(query_results: &[QueryResult]) -> Vec<(String, usize)> {
+    let mut map_location_to_count: BTreeMap<String, usize> = BTreeMap::new();
+    for author in get_unique_authors(query_results).values() {
+        let location = author.location.as_deref().unwrap_or_default().trim();
+        if !location.is_empty() {
+            *map_location_to_count
+                .entry(location.to_string())
+                .or_insert(0) += 1;
+        }
+    }
+    let mut sorted_values = Vec::from_iter(map_location_to_count);
+    // Count should be in decreasing order
+    sorted_values.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+    sorted_values
+}
+
+/// Keeps one copy of each tweet, the one fetched last so it has the latest retweet and favorite
+/// counts. Tweets stored before ids were tracked can't be told apart and are all kept. Returns
+/// the results along with how many copies were dropped
+fn dedupe_tweets(mut query_results: Vec<QueryResult>) -> (Vec<QueryResult>, usize) {
+    // Index of the newest result holding each tweet
+    let mut newest: BTreeMap<u64, usize> = BTreeMap::new();
+    for (i, result) in query_results.iter().enumerate() {
+        for tweet in result.tweets.iter().filter(|tweet| tweet.id != 0) {
+            let j = newest.entry(tweet.id).or_insert(i);
+            if query_results[*j].date_utc <= result.date_utc {
+                *j = i;
+            }
+        }
+    }
+    let mut duplicate_count = 0;
+    for (i, result) in query_results.iter_mut().enumerate() {
+        result.tweets.retain(|tweet| {
+            if tweet.id == 0 {
+                true
+            } else if newest.get(&tweet.id) == Some(&i) {
+                // Removed once kept, so copies within the same result go too
+                newest.remove(&tweet.id);
+                true
+            } else {
+                duplicate_count += 1;
+                false
+            }
+        });
+        result.prune_authors();
+    }
+    (query_results, duplicate_count)
+}
+
+/// Drops retweets or all but the first retweet of each status, depending on `mode`, along with
+/// the authors left without tweets
+fn apply_retweet_mode(mut query_results: Vec<QueryResult>, mode: RetweetMode) -> Vec<QueryResult> {
+    let mut seen: BTreeSet<String> = BTreeSet::new();
+    for result in query_results.iter_mut() {
+        result.tweets.retain(|tweet| match mode {
+            RetweetMode::Count => true,
+            RetweetMode::Exclude => !tweet.is_retweet(),
+            // Retweets stored before the retweeted id was kept can only be told apart by text
+            RetweetMode::Collapse => {
+                !tweet.is_retweet()
+                    || seen.insert(
+                        tweet
+                            .retweeted_status_id
+                            .map_or_else(|| tweet.full_text().to_string(), |id| id.to_string()),
+                    )
+            }
+        });
+        result.prune_authors();
+    }
+    query_results
+}
+
 /// Finds the most common words in a given search
 pub fn get_most_common_words(
     query_results: &[QueryResult],
@@ -195,7 +780,7 @@ pub fn get_most_common_words(
     for query in query_results {
         for tweet in &query.tweets {
             // Normalize text (somewhat)
-            let words = tweet.text.split_whitespace().collect::<Vec<&str>>();
+            let words = tweet.full_text().split_whitespace().collect::<Vec<&str>>();
 
             // Analyze each word
             for word in words {
@@ -214,7 +799,7 @@ pub fn get_most_common_words(
     // https://stackoverflow.com/questions/41220872/how-if-possible-to-sort-a-btreemap-by-value-in-rust
     let mut sorted_values = Vec::from_iter(map_word_to_count);
     // Count should be in decreasing order
-    sorted_values.sort_unstable_by(|&(_, a), &(_, b)| b.cmp(&a));
+    sorted_values.sort_unstable_by_key(|&(_, count)| std::cmp::Reverse(count));
     sorted_values
 }
 
@@ -235,15 +820,23 @@ pub fn get_most_common_handle_patterns(
     }
     let mut sorted_values = Vec::from_iter(map_pattern_to_count);
     // Count should be in decreasing order
-    sorted_values.sort_unstable_by(|&(_, a), &(_, b)| b.cmp(&a));
+    sorted_values.sort_unstable_by_key(|&(_, count)| std::cmp::Reverse(count));
     sorted_values
 }
 
 #[cfg(test)]
 mod test {
-    use super::{get_most_common_handle_patterns, get_most_common_words, HandlePattern};
-    use crate::twitter::QueryResult;
-    use crate::util::test::get_dummy_query_result;
+    use super::{
+        apply_retweet_mode, dedupe_tweets, get_account_age_histogram,
+        get_most_common_handle_patterns, get_most_common_locations, get_most_common_words,
+        AccountAge, HandlePattern, RetweetMode, SearchAnalysis, TimeWindow, VocabularyComparison,
+    };
+    use crate::error::Error;
+    use crate::query_path::analysis_dir_name;
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::twitter::{Config, QueryKind, QueryResult};
+    use crate::util::test::{get_dummy_query_result, get_dummy_search_analysis};
+    use chrono::{TimeZone, Utc};
     use std::cmp::Ordering;
 
     #[tokio::test]
@@ -258,7 +851,7 @@ mod test {
     async fn test_most_common_words_order() {
         let queries: Vec<QueryResult> = vec![get_dummy_query_result()];
         let words = get_most_common_words(&queries, &Vec::new());
-        assert_eq!(words.is_empty(), false);
+        assert!(!words.is_empty());
 
         println!("Words: {:?}", words);
         // Check ordering of elements, the earlier items should be greater than the succeeding ones
@@ -318,4 +911,289 @@ mod test {
     async fn test_handle_other() {
         assert_eq!(HandlePattern::from("123o%her"), HandlePattern::Other);
     }
+
+    #[tokio::test]
+    async fn test_vocabulary_comparison() {
+        let mut timeline = get_dummy_search_analysis();
+        timeline.word_frequency = vec![("hello".to_string(), 3), ("launch".to_string(), 2)];
+        let mut search = get_dummy_search_analysis();
+        search.word_frequency = vec![("hello".to_string(), 5), ("outage".to_string(), 4)];
+
+        let comparison = VocabularyComparison::new("@twitter", &timeline, &search);
+        assert_eq!(comparison.shared, vec!["hello".to_string()]);
+        assert_eq!(comparison.only_own, vec!["launch".to_string()]);
+        assert_eq!(comparison.only_mentions, vec!["outage".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_account_age() {
+        let now = chrono::Utc::now();
+        assert_eq!(
+            AccountAge::from(now - chrono::Duration::days(3), now),
+            AccountAge::LessThanAMonth
+        );
+        assert_eq!(
+            AccountAge::from(now - chrono::Duration::days(3 * 365), now),
+            AccountAge::TwoToFiveYears
+        );
+        assert_eq!(
+            AccountAge::from(now - chrono::Duration::days(15 * 365), now),
+            AccountAge::OverTenYears
+        );
+    }
+
+    #[tokio::test]
+    // The same author in two results is only counted once
+    async fn test_account_age_histogram_unique_authors() {
+        let queries: Vec<QueryResult> = vec![get_dummy_query_result(), get_dummy_query_result()];
+        let ages = get_account_age_histogram(&queries, chrono::Utc::now());
+        assert_eq!(ages, vec![(AccountAge::TwoToFiveYears, 1)]);
+    }
+
+    #[tokio::test]
+    async fn test_most_common_locations() {
+        let queries: Vec<QueryResult> = vec![get_dummy_query_result()];
+        let locations = get_most_common_locations(&queries);
+        assert_eq!(locations, vec![("Pittsburgh, PA".to_string(), 1)]);
+    }
+
+    #[tokio::test]
+    // Legacy "RT @user:" prefixes and the truncated last word don't end up as words
+    async fn test_most_common_words_full_text() {
+        let queries: Vec<QueryResult> = vec![get_dummy_query_result()];
+        let words = get_most_common_words(&queries, &Vec::new());
+        assert!(!words
+            .iter()
+            .any(|(word, _)| word == "rt" || word == "@twitter"));
+        assert!(!words.iter().any(|(word, _)| word.contains('…')));
+    }
+
+    #[tokio::test]
+    async fn test_retweet_modes() {
+        let queries: Vec<QueryResult> = vec![get_dummy_query_result(), get_dummy_query_result()];
+        let n_tweets = |mode| -> usize {
+            apply_retweet_mode(queries.clone(), mode)
+                .iter()
+                .map(|result| result.tweets.len())
+                .sum()
+        };
+        assert_eq!(n_tweets(RetweetMode::Count), 2);
+        assert_eq!(n_tweets(RetweetMode::Collapse), 1);
+        assert_eq!(n_tweets(RetweetMode::Exclude), 0);
+    }
+
+    #[tokio::test]
+    // Only the accounts of the category end up in its analysis
+    async fn test_category_analysis() {
+        let storage = MemoryStorage::new();
+        let mut other = get_dummy_query_result();
+        other.query = "@BBCWorld".to_string();
+        storage.save_query(&get_dummy_query_result()).unwrap();
+        storage.save_query(&other).unwrap();
+
+        let accounts: Config = serde_json::from_str(
+            r#"{"categories": ["test", "news"], "accounts": [
+                {"handle": "@twitter", "category": "test"},
+                {"handle": "@BBCWorld", "category": "news"}
+            ]}"#,
+        )
+        .unwrap();
+        let analysis = SearchAnalysis::from_stored_category(
+            &storage,
+            &[],
+            "test",
+            &accounts,
+            QueryKind::Search,
+            RetweetMode::Count,
+            TimeWindow::default(),
+        )
+        .expect("Could not analyze category");
+        assert_eq!(analysis.category, Some("test".to_string()));
+        assert_eq!(analysis.queries, vec!["@twitter".to_string()]);
+    }
+
+    #[tokio::test]
+    // Polling a query once more doesn't move its analyses to another directory
+    async fn test_analysis_queries_are_unique() {
+        let analyze = |n_results: usize| {
+            let mut query_results = vec![get_dummy_query_result(); n_results];
+            query_results[0].query = "@nytimes".to_string();
+            SearchAnalysis::from_query_results(
+                query_results,
+                &[],
+                QueryKind::Search,
+                RetweetMode::Count,
+                TimeWindow::default(),
+            )
+            .unwrap()
+        };
+        let (fewer, more) = (analyze(20), analyze(21));
+        assert_eq!(
+            fewer.queries,
+            vec!["@nytimes".to_string(), "@twitter".to_string()]
+        );
+        assert_eq!(
+            analysis_dir_name(&fewer.queries, None),
+            analysis_dir_name(&more.queries, None)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_time_window_args() {
+        let now = Utc.with_ymd_and_hms(2020, 12, 8, 12, 0, 0).unwrap();
+        let window = TimeWindow::from_args(None, None, Some("7d"), now).unwrap();
+        assert_eq!(
+            window.since,
+            Some(Utc.with_ymd_and_hms(2020, 12, 1, 12, 0, 0).unwrap())
+        );
+        let window =
+            TimeWindow::from_args(Some("2020-12-01"), Some("2020-12-02T06:00:00Z"), None, now)
+                .unwrap();
+        assert_eq!(
+            window.since,
+            Some(Utc.with_ymd_and_hms(2020, 12, 1, 0, 0, 0).unwrap())
+        );
+        assert_eq!(
+            window.until,
+            Some(Utc.with_ymd_and_hms(2020, 12, 2, 6, 0, 0).unwrap())
+        );
+        assert!(TimeWindow::from_args(None, None, Some("7 days"), now).is_err());
+        // Too far back for a date or a duration
+        assert!(matches!(
+            TimeWindow::from_args(None, None, Some("100000000d"), now),
+            Err(Error::Usage(_))
+        ));
+        assert!(matches!(
+            TimeWindow::from_args(None, None, Some("99999999999999999w"), now),
+            Err(Error::Usage(_))
+        ));
+        assert!(TimeWindow::from_args(Some("2020-12-02"), Some("2020-12-01"), None, now).is_err());
+    }
+
+    #[tokio::test]
+    // Tweets outside the window are left out and the window is recorded in the analysis
+    async fn test_time_window_analysis() {
+        let storage = MemoryStorage::new();
+        let mut result = get_dummy_query_result();
+        result.date_utc = Utc.with_ymd_and_hms(2020, 12, 10, 0, 0, 0).unwrap();
+        let mut old_tweet = result.tweets[0].clone();
+        old_tweet.id += 1;
+        old_tweet.text = "ancient".to_string();
+        old_tweet.date_utc = Utc.with_ymd_and_hms(2020, 11, 1, 0, 0, 0).unwrap();
+        result.tweets[0].date_utc = Utc.with_ymd_and_hms(2020, 12, 5, 0, 0, 0).unwrap();
+        result.tweets.push(old_tweet);
+        storage.save_query(&result).unwrap();
+
+        let window = TimeWindow {
+            since: Some(Utc.with_ymd_and_hms(2020, 12, 1, 0, 0, 0).unwrap()),
+            until: None,
+        };
+        let analysis = SearchAnalysis::from_stored_queries(
+            &storage,
+            &[],
+            QueryKind::Search,
+            RetweetMode::Count,
+            window,
+        )
+        .unwrap();
+        assert_eq!(analysis.window, window);
+        assert!(!analysis
+            .word_frequency
+            .iter()
+            .any(|(word, _)| word == "ancient"));
+        assert!(analysis
+            .summary()
+            .contains("Covering tweets posted since 2020-12-01"));
+
+        // Nothing was posted in this window
+        let window = TimeWindow {
+            since: None,
+            until: Some(Utc.with_ymd_and_hms(2020, 10, 1, 0, 0, 0).unwrap()),
+        };
+        assert!(SearchAnalysis::from_stored_queries(
+            &storage,
+            &[],
+            QueryKind::Search,
+            RetweetMode::Count,
+            window
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    // Authors of tweets outside the window don't show up in the author histograms
+    async fn test_time_window_prunes_authors() {
+        let mut result = get_dummy_query_result();
+        let mut old_tweet = result.tweets[0].clone();
+        old_tweet.id += 1;
+        old_tweet.handle = "oldHandle".to_string();
+        old_tweet.date_utc = Utc.with_ymd_and_hms(2020, 11, 1, 0, 0, 0).unwrap();
+        let old_author = result.authors["fakeHandle"].clone();
+        result.tweets.push(old_tweet);
+        result.authors.insert("oldHandle".to_string(), old_author);
+
+        let window = TimeWindow {
+            since: Some(Utc.with_ymd_and_hms(2020, 12, 1, 0, 0, 0).unwrap()),
+            until: None,
+        };
+        let filtered = window.apply(vec![result]);
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(
+            filtered[0].authors.keys().collect::<Vec<_>>(),
+            vec!["fakeHandle"]
+        );
+    }
+
+    #[tokio::test]
+    // Polling the same query twice doesn't count its tweets twice
+    async fn test_dedupe_tweets() {
+        let mut older = get_dummy_query_result();
+        older.date_utc = Utc.with_ymd_and_hms(2020, 12, 1, 0, 0, 0).unwrap();
+        older.tweets[0].retweet_count = 1;
+        let mut newer = get_dummy_query_result();
+        newer.date_utc = Utc.with_ymd_and_hms(2020, 12, 2, 0, 0, 0).unwrap();
+        newer.tweets[0].retweet_count = 5;
+        let mut other = newer.tweets[0].clone();
+        other.id += 1;
+        newer.tweets.push(other);
+
+        // The newer result comes first, its copy still wins
+        let (query_results, duplicate_count) = dedupe_tweets(vec![newer, older]);
+        assert_eq!(duplicate_count, 1);
+        assert_eq!(query_results[0].tweets.len(), 2);
+        assert_eq!(query_results[0].tweets[0].retweet_count, 5);
+        assert!(query_results[1].tweets.is_empty());
+
+        let storage = MemoryStorage::new();
+        storage.save_query(&get_dummy_query_result()).unwrap();
+        storage.save_query(&get_dummy_query_result()).unwrap();
+        let analysis = SearchAnalysis::from_stored_queries(
+            &storage,
+            &[],
+            QueryKind::Search,
+            RetweetMode::Count,
+            TimeWindow::default(),
+        )
+        .unwrap();
+        assert_eq!(analysis.duplicate_count, 1);
+        assert!(analysis
+            .summary()
+            .contains("1 duplicate tweets were removed"));
+    }
+
+    #[tokio::test]
+    // Tweets stored before ids were tracked all have id 0, none of them is a copy of another
+    async fn test_dedupe_legacy_tweets() {
+        let mut first = get_dummy_query_result();
+        first.tweets[0].id = 0;
+        let mut second = get_dummy_query_result();
+        second.tweets[0].id = 0;
+        second.tweets[0].text = "another legacy tweet".to_string();
+        second.tweets.push(second.tweets[0].clone());
+
+        let (query_results, duplicate_count) = dedupe_tweets(vec![first, second]);
+        assert_eq!(duplicate_count, 0);
+        assert_eq!(query_results[0].tweets.len(), 1);
+        assert_eq!(query_results[1].tweets.len(), 2);
+    }
 }
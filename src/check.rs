@@ -0,0 +1,226 @@
+use crate::analysis::{AnalysisConfig, SUPPORTED_ANALYSIS_CONFIG_VERSION};
+use crate::error::{Error, Result};
+use crate::twitter::Config;
+use std::{collections::BTreeMap, path::Path};
+
+/// Parse `path` as JSON, problems are reported as `path:line:column: message`
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> std::result::Result<T, String> {
+    let json_str = std::fs::read_to_string(path)
+        .map_err(|e| format!("{}: could not read: {}", path.display(), e))?;
+    serde_json::from_str(&json_str).map_err(|e| {
+        // serde_json appends " at line X column Y" to its messages, it's moved to the front
+        let message = e.to_string();
+        let message = message
+            .rsplit_once(" at line ")
+            .map_or(message.as_str(), |(message, _)| message);
+        format!(
+            "{}:{}:{}: {}",
+            path.display(),
+            e.line(),
+            e.column(),
+            message
+        )
+    })
+}
+
+/// Everything wrong with the accounts configuration, empty if it's fine
+pub fn check_accounts(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut handles: BTreeMap<String, usize> = BTreeMap::new();
+    for (i, account) in config.accounts.iter().enumerate() {
+        if !account.handle.starts_with('@') {
+            problems.push(format!(
+                "accounts[{}]: handle {:?} should start with \"@\"",
+                i, account.handle
+            ));
+        }
+        if !config.categories.contains(&account.category) {
+            problems.push(format!(
+                "accounts[{}]: category {:?} of {} is not declared in categories {:?}",
+                i, account.category, account.handle, config.categories
+            ));
+        }
+        // Handles are case insensitive on Twitter
+        let handle = account.handle.trim_start_matches('@').to_lowercase();
+        if let Some(first) = handles.insert(handle, i) {
+            problems.push(format!(
+                "accounts[{}]: {} is already listed as accounts[{}]",
+                i, account.handle, first
+            ));
+        }
+    }
+    for category in config.watch_intervals.keys() {
+        if !config.categories.contains(category) {
+            problems.push(format!(
+                "watch_intervals: category {:?} is not declared in categories {:?}",
+                category, config.categories
+            ));
+        }
+    }
+    problems
+}
+
+/// Keys of the accounts configuration this version doesn't know about. They're kept when
+/// saving but do nothing, so a misspelled search option would go unnoticed without a warning
+pub fn unknown_account_keys(config: &Config) -> Vec<String> {
+    let mut warnings: Vec<String> = config
+        .extra
+        .keys()
+        .map(|key| format!("unknown key {:?} is ignored", key))
+        .collect();
+    for (i, account) in config.accounts.iter().enumerate() {
+        for key in account.extra.keys() {
+            warnings.push(format!(
+                "accounts[{}]: unknown key {:?} of {} is ignored",
+                i, key, account.handle
+            ));
+        }
+    }
+    warnings
+}
+
+/// Everything wrong with the analysis configuration, empty if it's fine
+pub fn check_analysis_config(config: &AnalysisConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    if config.version != SUPPORTED_ANALYSIS_CONFIG_VERSION {
+        problems.push(format!(
+            "version {} is not supported, expected {}",
+            config.version, SUPPORTED_ANALYSIS_CONFIG_VERSION
+        ));
+    }
+    let mut words: BTreeMap<&str, usize> = BTreeMap::new();
+    for (i, word) in config.ignored_words.iter().enumerate() {
+        // Words are lowercased before they're compared with these
+        if word.to_lowercase() != *word {
+            problems.push(format!(
+                "ignored_words[{}]: {:?} is never matched, it should be lowercase",
+                i, word
+            ));
+        }
+        if let Some(first) = words.insert(word, i) {
+            problems.push(format!(
+                "ignored_words[{}]: {:?} is already listed as ignored_words[{}]",
+                i, word, first
+            ));
+        }
+    }
+    problems
+}
+
+/// Check both configuration files and print what's wrong with them
+pub fn run_config_check(accounts_path: &Path, analysis_config_path: &Path) -> Result<()> {
+    let mut n_problems = 0;
+    let mut report = |path: &Path, problems: Vec<String>| {
+        if problems.is_empty() {
+            println!("{}: OK", path.display());
+        }
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        n_problems += problems.len();
+    };
+
+    match read_json::<Config>(accounts_path) {
+        Ok(config) => {
+            // Only warned about, they don't count as problems
+            for warning in unknown_account_keys(&config) {
+                println!("{}: warning: {}", accounts_path.display(), warning);
+            }
+            report(
+                accounts_path,
+                check_accounts(&config)
+                    .into_iter()
+                    .map(|problem| format!("{}: {}", accounts_path.display(), problem))
+                    .collect(),
+            )
+        }
+        Err(problem) => report(accounts_path, vec![problem]),
+    }
+    match read_json::<AnalysisConfig>(analysis_config_path) {
+        Ok(config) => report(
+            analysis_config_path,
+            check_analysis_config(&config)
+                .into_iter()
+                .map(|problem| format!("{}: {}", analysis_config_path.display(), problem))
+                .collect(),
+        ),
+        Err(problem) => report(analysis_config_path, vec![problem]),
+    }
+
+    if n_problems > 0 {
+        return Err(Error::Config(format!("Found {} problem(s)", n_problems)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_accounts, check_analysis_config, read_json, unknown_account_keys};
+    use crate::analysis::AnalysisConfig;
+    use crate::twitter::Config;
+    use crate::util::test::get_test_dir;
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn test_shipped_config_is_valid() {
+        let accounts: Config = read_json(Path::new("conf/accounts.json")).unwrap();
+        assert_eq!(check_accounts(&accounts), Vec::<String>::new());
+        assert_eq!(unknown_account_keys(&accounts), Vec::<String>::new());
+        let analysis: AnalysisConfig = read_json(Path::new("conf/analysis.json")).unwrap();
+        assert_eq!(check_analysis_config(&analysis), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_account_problems() {
+        let accounts: Config = serde_json::from_str(
+            r#"{"categories": ["politicians"], "accounts": [
+                {"handle": "@billpeduto", "category": "politician"},
+                {"handle": "BillPeduto", "category": "politicians"}
+            ]}"#,
+        )
+        .unwrap();
+        let problems = check_accounts(&accounts);
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].contains("\"politician\" of @billpeduto is not declared"));
+        assert!(problems[1].contains("should start with \"@\""));
+        assert!(problems[2].contains("already listed as accounts[0]"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_account_keys() {
+        let accounts: Config = serde_json::from_str(
+            r#"{"categories": ["news"], "comment": "hand written", "accounts": [
+                {"handle": "@BBCWorld", "category": "news", "lang": "en", "result_typ": "popular"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            unknown_account_keys(&accounts),
+            vec![
+                "unknown key \"comment\" is ignored".to_string(),
+                "accounts[0]: unknown key \"result_typ\" of @BBCWorld is ignored".to_string(),
+            ]
+        );
+        assert_eq!(check_accounts(&accounts), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn test_analysis_config_problems() {
+        let config: AnalysisConfig = serde_json::from_str(
+            r#"{"version": 2, "description": "", "ignored_words": ["the", "The", "the"]}"#,
+        )
+        .unwrap();
+        let problems = check_analysis_config(&config);
+        assert_eq!(problems.len(), 3);
+        assert!(problems[0].contains("version 2 is not supported"));
+    }
+
+    #[tokio::test]
+    async fn test_json_error_position() {
+        let test_dir = get_test_dir("test_json_error_position");
+        let path = test_dir.join("accounts.json");
+        std::fs::write(&path, "{\n  \"categories\": [],\n  \"accounts\": [,]\n}").unwrap();
+        let problem = read_json::<Config>(&path).unwrap_err();
+        assert!(problem.starts_with(&format!("{}:3:16: ", path.display())));
+    }
+}
@@ -0,0 +1,177 @@
+use std::{fmt, io};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Twitter error codes that mean the token itself is the problem
+/// Reference: https://developer.twitter.com/en/support/twitter-api/error-troubleshooting
+const AUTH_ERROR_CODES: [i32; 4] = [32, 89, 99, 215];
+/// Seconds to wait out a 429 that didn't say when the window resets
+const DEFAULT_RATE_LIMIT_BACKOFF_SECS: i64 = 60;
+
+/// Every failure twitter-analyzer can run into, each one maps to its own exit code
+#[derive(Debug)]
+pub enum Error {
+    /// Usage error on the command line
+    Usage(String),
+    /// Twitter couldn't be reached or gave back an error that isn't covered below
+    Network(egg_mode::error::Error),
+    /// The token is missing, malformed or was rejected by Twitter
+    Auth(String),
+    /// Twitter refused the call, holds the UTC Unix timestamp of the next window
+    RateLimit(i32),
+    /// A response or stored file couldn't be parsed
+    Parse(String),
+    /// A configuration file couldn't be read or is invalid
+    Config(String),
+    /// Reading from or writing to the storage directory or database failed
+    Storage(io::Error),
+    /// There was nothing to work with, e.g. no stored queries to analyze
+    NoData(String),
+    /// Twitter refused access to the tweets of one query, like the timeline of a protected
+    /// account. Unlike `Auth` the token is fine, other queries work with it
+    Refused(String),
+}
+
+impl Error {
+    /// Process exit code for this error, so wrapper scripts can tell failures apart
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Usage(_) => 1,
+            Error::Network(_) => 2,
+            Error::Auth(_) => 3,
+            Error::RateLimit(_) => 4,
+            Error::Parse(_) => 5,
+            Error::Config(_) => 6,
+            Error::Storage(_) => 7,
+            Error::NoData(_) => 8,
+            Error::Refused(_) => 9,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Usage(msg) => write!(f, "Usage error: {}", msg),
+            Error::Network(e) => write!(f, "Network error: {}", e),
+            Error::Auth(msg) => write!(f, "Authentication error: {}", msg),
+            Error::RateLimit(reset) => write!(f, "Rate limit reached, resets at {}", reset),
+            Error::Parse(msg) => write!(f, "Parse error: {}", msg),
+            Error::Config(msg) => write!(f, "Configuration error: {}", msg),
+            Error::Storage(e) => write!(f, "Storage error: {}", e),
+            Error::NoData(msg) => write!(f, "No data: {}", msg),
+            Error::Refused(msg) => write!(f, "Access refused: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Storage(e)
+    }
+}
+
+/// The database is part of the storage, so its failures share the storage exit code
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Storage(io::Error::other(e))
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Parse(e.to_string())
+    }
+}
+
+impl From<egg_mode::error::Error> for Error {
+    fn from(e: egg_mode::error::Error) -> Error {
+        use egg_mode::error::Error as EggError;
+        match e {
+            EggError::RateLimit(reset) => Error::RateLimit(reset),
+            // egg-mode only reads the reset time for 429s with error code 88 in the body
+            EggError::BadStatus(status) if status.as_u16() == 429 => Error::RateLimit(
+                (chrono::Utc::now().timestamp() + DEFAULT_RATE_LIMIT_BACKOFF_SECS) as i32,
+            ),
+            // A rejected token comes with one of the error codes above, a bare 401 or 403 is
+            // about the requested tweets
+            EggError::BadStatus(status) if status.as_u16() == 401 || status.as_u16() == 403 => {
+                Error::Refused(format!("Twitter responded with {}", status))
+            }
+            EggError::TwitterError(_, ref errors)
+                if errors
+                    .errors
+                    .iter()
+                    .any(|error| AUTH_ERROR_CODES.contains(&error.code)) =>
+            {
+                Error::Auth(errors.to_string())
+            }
+            EggError::InvalidResponse(..)
+            | EggError::MissingValue(_)
+            | EggError::DeserializeError(_)
+            | EggError::TimestampParseError(_) => Error::Parse(e.to_string()),
+            e => Error::Network(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Error;
+    use std::collections::BTreeSet;
+
+    #[tokio::test]
+    async fn test_exit_codes_are_distinct() {
+        let errors = [
+            Error::Usage(String::new()),
+            Error::Network(egg_mode::error::Error::BadUrl),
+            Error::Auth(String::new()),
+            Error::RateLimit(0),
+            Error::Parse(String::new()),
+            Error::Config(String::new()),
+            Error::Storage(std::io::Error::other("")),
+            Error::NoData(String::new()),
+            Error::Refused(String::new()),
+        ];
+        let codes: BTreeSet<i32> = errors.iter().map(Error::exit_code).collect();
+        assert_eq!(codes.len(), errors.len());
+        assert!(!codes.contains(&0));
+    }
+
+    #[tokio::test]
+    async fn test_egg_mode_rate_limit() {
+        let error = Error::from(egg_mode::error::Error::RateLimit(1234));
+        assert!(matches!(error, Error::RateLimit(1234)));
+    }
+
+    #[tokio::test]
+    // A 429 without a reset time is still a rate limit, waited out for a while from now
+    async fn test_egg_mode_bad_status_rate_limit() {
+        let now = chrono::Utc::now().timestamp() as i32;
+        let error = Error::from(egg_mode::error::Error::BadStatus(
+            hyper::StatusCode::TOO_MANY_REQUESTS,
+        ));
+        assert!(matches!(error, Error::RateLimit(reset) if reset > now));
+    }
+
+    #[tokio::test]
+    // Only error codes about the token mean it was rejected, a bare 401 is about the tweets
+    async fn test_egg_mode_auth_error() {
+        assert!(matches!(
+            crate::util::test::get_rejected_token_error(),
+            Error::Auth(_)
+        ));
+        let error = Error::from(egg_mode::error::Error::BadStatus(
+            hyper::StatusCode::UNAUTHORIZED,
+        ));
+        assert!(matches!(error, Error::Refused(_)));
+    }
+
+    #[tokio::test]
+    async fn test_egg_mode_parse_error() {
+        let error = Error::from(egg_mode::error::Error::MissingValue("id"));
+        assert!(matches!(error, Error::Parse(_)));
+    }
+}
@@ -1,21 +1,128 @@
+mod accounts;
 mod analysis;
+mod check;
+mod error;
+mod query_path;
+mod rate_limit;
+mod retry;
+mod settings;
+mod source;
+mod sqlite;
 mod storage;
+mod stream;
 mod twitter;
 mod util;
+mod watch;
 
 extern crate clap;
-use analysis::{run_analysis_on_query, run_analysis_with_config, AnalysisConfig};
-use clap::{App, Arg, SubCommand};
+use analysis::{
+    list_analyses, run_analysis_on_categories, run_analysis_on_query, run_analysis_with_config,
+    run_vocabulary_comparison, AnalysisConfig, TimeWindow,
+};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use error::{Error, Result};
+use retry::{RetryPolicy, RetryingSource};
+use settings::{Settings, SettingsLayer};
+use source::{EggModeSource, ReplaySource, TweetSource};
 use std::path::Path;
 use std::process::exit;
+use storage::StorageHandler;
 use twitter::*;
 
+/// Where files are read from and written to, accepted by every subcommand
+fn settings_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("settings")
+            .long("settings")
+            .value_name("SETTINGS_PATH")
+            .global(true)
+            .help("JSON settings file, also read from $TWITTER_ANALYZER_SETTINGS"),
+        Arg::with_name("storage_dir")
+            .long("storage-dir")
+            .value_name("STORAGE_DIR")
+            .global(true)
+            .help("Where queries and analyses are stored, also read from $TWITTER_ANALYZER_STORAGE_DIR"),
+        Arg::with_name("storage_backend")
+            .long("storage-backend")
+            .value_name("BACKEND")
+            .possible_values(&["files", "sqlite"])
+            .global(true)
+            .help("How the storage dir is laid out, JSON files or one SQLite database. Also read from $TWITTER_ANALYZER_STORAGE_BACKEND"),
+        Arg::with_name("accounts")
+            .long("accounts")
+            .value_name("ACCOUNTS_PATH")
+            .global(true)
+            .help("Accounts to search, also read from $TWITTER_ANALYZER_ACCOUNTS"),
+        Arg::with_name("analysis_config")
+            .long("analysis-config")
+            .value_name("ANALYSIS_CONFIG_PATH")
+            .global(true)
+            .help("Analysis configuration, also read from $TWITTER_ANALYZER_ANALYSIS_CONFIG"),
+        Arg::with_name("token")
+            .short("t")
+            .long("token")
+            .alias("bearer-token")
+            .value_name("TOKEN_PATH")
+            .global(true)
+            .help("File containing the bearer token, requested with the consumer keys if missing. Also read from $TWITTER_ANALYZER_TOKEN"),
+    ]
+}
+
+/// Arguments shared by every subcommand that fetches tweets
+fn fetch_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        token_kind_arg(),
+        Arg::with_name("pages")
+            .short("p")
+            .long("pages")
+            .value_name("N_PAGES")
+            .help("Pages of tweets to fetch per query, overrides the accounts file"),
+        Arg::with_name("concurrency")
+            .short("c")
+            .long("concurrency")
+            .value_name("N_SEARCHES")
+            .help("Searches to run at the same time, overrides the accounts file"),
+        Arg::with_name("source")
+            .short("s")
+            .long("source")
+            .value_name("SOURCE")
+            .possible_values(&["twitter", "replay"])
+            .default_value("twitter")
+            .requires_if("replay", "fixture")
+            .help("Where tweets come from, \"replay\" reads recorded results instead of calling Twitter"),
+        Arg::with_name("fixture")
+            .short("f")
+            .long("fixture")
+            .value_name("FIXTURE_PATH")
+            .help("Recorded query-result.json file or storage directory to replay"),
+        category_arg().help("Only fetch the accounts in this category, can be repeated"),
+    ]
+}
+
+fn token_kind_arg() -> Arg<'static, 'static> {
+    Arg::with_name("token_kind")
+        .long("token-kind")
+        .value_name("TOKEN_KIND")
+        .possible_values(&["bearer", "access"])
+        .default_value("bearer")
+        .help("Authenticate as the app with a bearer token, or as the user logged in with \"auth login\"")
+}
+
+fn category_arg() -> Arg<'static, 'static> {
+    Arg::with_name("category")
+        .long("category")
+        .value_name("CATEGORY")
+        .multiple(true)
+        .number_of_values(1)
+}
+
 #[tokio::main]
 async fn main() {
     let matches = App::new("twitter-analyzer")
         .version("0.1")
         .author("Mike Kaliman <kaliman.mike@gmail.com>")
         .about("Finds common words and handles in a twitter search")
+        .args(&settings_args())
         .subcommand(
             SubCommand::with_name("analyze")
                 .about("Search twitter using a query and print analysis")
@@ -23,6 +130,60 @@ async fn main() {
                     Arg::with_name("analyze_command")
                         .value_name("ANALYZE_COMMAND")
                         .help("Command for analyzing twitter queries"),
+                )
+                .arg(
+                    Arg::with_name("kind")
+                        .short("k")
+                        .long("kind")
+                        .value_name("KIND")
+                        .possible_values(&["search", "timeline", "stream"])
+                        .default_value("search")
+                        .help("Analyze searches for the query, the timeline of the account or streamed tweets"),
+                )
+                .arg(
+                    Arg::with_name("compare")
+                        .long("compare")
+                        .requires("analyze_command")
+                        .conflicts_with("kind")
+                        .help("Compare the account's own vocabulary with the vocabulary of people mentioning it"),
+                )
+                .arg(
+                    category_arg()
+                        .conflicts_with("analyze_command")
+                        .help("Analyze all accounts in this category together, can be repeated for one analysis per category"),
+                )
+                .arg(
+                    Arg::with_name("match")
+                        .long("match")
+                        .value_name("MATCH")
+                        .possible_values(&["exact", "prefix", "glob"])
+                        .requires("analyze_command")
+                        .help("How stored queries are matched against ANALYZE_COMMAND, glob supports * and ?. Defaults to exact"),
+                )
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .value_name("DATE")
+                        .help("Only analyze tweets posted since DATE, like 2020-12-01 or 2020-12-01T13:37:00Z"),
+                )
+                .arg(
+                    Arg::with_name("until")
+                        .long("until")
+                        .value_name("DATE")
+                        .help("Only analyze tweets posted before DATE"),
+                )
+                .arg(
+                    Arg::with_name("last")
+                        .long("last")
+                        .value_name("DURATION")
+                        .conflicts_with("since")
+                        .help("Only analyze tweets posted in the last DURATION, like 30m, 12h, 7d or 2w"),
+                )
+                .arg(
+                    Arg::with_name("list")
+                        .long("list")
+                        .conflicts_with_all(&["analyze_command", "category"])
+                        .help("List the stored analyses instead of running a new one"),
                 ),
         )
         .subcommand(
@@ -33,12 +194,128 @@ async fn main() {
                         .value_name("SEARCH_QUERY")
                         .help("Search query. Can include \"@\" if needed. Example: @twitter"),
                 )
+                .args(&fetch_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("timeline")
+                .about("Fetch the tweets posted by an account, or by every account in the accounts file")
+                .arg(
+                    Arg::with_name("handle")
+                        .value_name("HANDLE")
+                        .help("Account handle including \"@\". Example: @twitter"),
+                )
+                .args(&fetch_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("auth")
+                .about("Manage Twitter credentials")
+                .subcommand(
+                    SubCommand::with_name("login")
+                        .about("Log in as a user with a PIN, the access token is saved for later runs"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Keep fetching the accounts in the accounts file until stopped with Ctrl+C or SIGTERM")
+                .arg(
+                    Arg::with_name("interval")
+                        .short("i")
+                        .long("interval")
+                        .value_name("MINUTES")
+                        .help("Minutes between fetches of categories without a \"watch_intervals\" entry, defaults to 15"),
+                )
+                .arg(
+                    Arg::with_name("analyze")
+                        .long("analyze")
+                        .help("Analyze each category after fetching it"),
+                )
+                .args(&fetch_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("stream")
+                .about("Collect tweets from and mentioning the accounts, and the keywords, as they're posted")
                 .arg(
-                    Arg::with_name("bearer_token")
-                        .short("t")
-                        .long("bearer-token")
-                        .value_name("TOKEN_PATH")
-                        .help("File containing the bearer token, do not include newlines in it"),
+                    Arg::with_name("bucket")
+                        .short("b")
+                        .long("bucket")
+                        .value_name("MINUTES")
+                        .help("Minutes of tweets per stored file, defaults to 10"),
+                )
+                .arg(category_arg().help("Only stream the accounts in this category, can be repeated"))
+                // The streaming API needs a user
+                .arg(token_kind_arg().default_value("access")),
+        )
+        .subcommand(
+            SubCommand::with_name("accounts")
+                .about("Manage the tracked accounts in the accounts file")
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Track an account")
+                        .arg(
+                            Arg::with_name("handle")
+                                .value_name("HANDLE")
+                                .required(true)
+                                .help("Account handle, \"@\" is optional"),
+                        )
+                        .arg(
+                            category_arg()
+                                .required(true)
+                                .multiple(false)
+                                .help("Category of the account, has to be declared already"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove").about("Stop tracking an account").arg(
+                        Arg::with_name("handle")
+                            .value_name("HANDLE")
+                            .required(true)
+                            .help("Account handle, \"@\" is optional"),
+                    ),
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("List tracked accounts")
+                        .arg(category_arg().help("Only list this category, can be repeated")),
+                )
+                .subcommand(
+                    SubCommand::with_name("import")
+                        .about("Track every account in a CSV file or a Twitter list")
+                        .arg(
+                            Arg::with_name("csv")
+                                .value_name("CSV_PATH")
+                                .required_unless("list")
+                                .conflicts_with("list")
+                                .help("CSV file with a \"handle\" and an optional \"category\" column"),
+                        )
+                        .arg(
+                            Arg::with_name("list")
+                                .long("list")
+                                .value_name("LIST")
+                                .requires("category")
+                                .help("Twitter list as \"owner/slug\" or its id"),
+                        )
+                        .arg(
+                            category_arg()
+                                .multiple(false)
+                                .help("Category for list members and CSV rows without one"),
+                        )
+                        .arg(token_kind_arg()),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Inspect the configuration files")
+                .subcommand(
+                    SubCommand::with_name("check")
+                        .about("Validate the accounts and analysis configuration"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("storage")
+                .about("Maintain the storage directory")
+                .subcommand(
+                    SubCommand::with_name("migrate")
+                        .about("Move results stored under raw query names into encoded directories"),
                 ),
         )
         .subcommand(
@@ -47,29 +324,270 @@ async fn main() {
         )
         .get_matches();
 
+    if let Err(e) = run(&matches).await {
+        eprintln!("{}", e);
+        exit(e.exit_code());
+    }
+}
+
+/// Parse an optional numeric argument, e.g. `--pages 3`
+fn parse_arg<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Result<Option<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    match matches.value_of(name) {
+        Some(value) => value
+            .parse::<T>()
+            .map(Some)
+            .map_err(|e| Error::Usage(format!("Could not parse --{} {:?}: {}", name, value, e))),
+        None => Ok(None),
+    }
+}
+
+/// Every `--category`, empty if there are none
+fn categories(matches: &ArgMatches) -> Vec<String> {
+    matches
+        .values_of("category")
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// Paths from the command line, the environment and the settings file
+fn get_settings(matches: &ArgMatches) -> Result<Settings> {
+    let path = |name: &str| matches.value_of(name).map(std::path::PathBuf::from);
+    let cli = SettingsLayer {
+        storage_dir: path("storage_dir"),
+        storage_backend: parse_arg(matches, "storage_backend")?,
+        accounts: path("accounts"),
+        analysis_config: path("analysis_config"),
+        token: path("token"),
+        ..SettingsLayer::default()
+    };
+    Settings::resolve(cli, matches.value_of("settings").map(Path::new), |name| {
+        std::env::var(name).ok()
+    })
+}
+
+/// Build the tweet source picked with `--source`
+async fn get_source(matches: &ArgMatches<'_>, settings: &Settings) -> Result<Box<dyn TweetSource>> {
+    match matches.value_of("source") {
+        Some("replay") => Ok(Box::new(ReplaySource::from_path(Path::new(
+            matches.value_of("fixture").unwrap_or_default(),
+        ))?)),
+        _ => {
+            let policy = RetryPolicy::from_accounts(&settings.accounts)?;
+            let source = EggModeSource::new(get_token(matches, settings).await?);
+            Ok(Box::new(RetryingSource::new(Box::new(source), policy)))
+        }
+    }
+}
+
+/// Token of the kind picked with `--token-kind`
+async fn get_token(matches: &ArgMatches<'_>, settings: &Settings) -> Result<egg_mode::Token> {
+    match parse_arg(matches, "token_kind")?.unwrap_or_default() {
+        auth::TokenKind::Bearer => {
+            auth::get_or_request_token(&settings.token, &settings.consumer_keys).await
+        }
+        auth::TokenKind::Access => auth::get_access_token(&settings.access_token),
+    }
+}
+
+async fn run_watch(matches: &ArgMatches<'_>, settings: &Settings) -> Result<()> {
+    let analysis = if matches.is_present("analyze") {
+        Some(AnalysisConfig::new(&settings.analysis_config)?)
+    } else {
+        None
+    };
+    let options = watch::WatchOptions {
+        accounts_path: settings.accounts.clone(),
+        default_interval: parse_arg(matches, "interval")?.unwrap_or(watch::DEFAULT_WATCH_INTERVAL),
+        categories: categories(matches),
+        max_pages: parse_arg(matches, "pages")?,
+        concurrency: parse_arg(matches, "concurrency")?,
+        analysis,
+    };
+    let source = get_source(matches, settings).await?;
+    let storage = settings.open_storage()?;
+    watch::run_watch(source.as_ref(), storage.as_ref(), options).await
+}
+
+/// Read, change and write back the accounts file
+async fn run_accounts(matches: &ArgMatches<'_>, settings: &Settings) -> Result<()> {
+    let mut config = Config::get(&settings.accounts)?;
+    match matches.subcommand() {
+        ("add", Some(matches)) => {
+            let handle = matches.value_of("handle").unwrap_or_default();
+            let category = matches.value_of("category").unwrap_or_default();
+            config.add_account(handle, category)?;
+            println!("Added {} to {:?}", handle, category);
+        }
+        ("remove", Some(matches)) => {
+            let removed = config.remove_account(matches.value_of("handle").unwrap_or_default())?;
+            println!("Removed {} from {:?}", removed.handle, removed.category);
+        }
+        ("list", Some(matches)) => return accounts::list_accounts(&config, &categories(matches)),
+        ("import", Some(matches)) => {
+            let category = matches.value_of("category");
+            let n_added = match (matches.value_of("csv"), matches.value_of("list")) {
+                (Some(csv_path), _) => config.import_csv(Path::new(csv_path), category)?,
+                (_, Some(list)) => {
+                    let token = get_token(matches, settings).await?;
+                    config
+                        .import_list(list, category.unwrap_or_default(), &token)
+                        .await?
+                }
+                (None, None) => 0,
+            };
+            println!("Imported {} new account(s)", n_added);
+        }
+        (_, _) => {
+            return Err(Error::Usage(
+                "Missing accounts subcommand. Use \"accounts --help\" to see them".to_string(),
+            ));
+        }
+    }
+    config.save(&settings.accounts)
+}
+
+/// Drop the cached bearer token if Twitter rejected it while fetching
+fn invalidate_rejected_token(
+    matches: &ArgMatches,
+    settings: &Settings,
+    result: Result<()>,
+) -> Result<()> {
+    if matches.value_of("source") == Some("twitter")
+        && matches.value_of("token_kind") == Some("bearer")
+        && auth::invalidate_rejected_token(&result, &settings.token, &settings.consumer_keys)?
+    {
+        eprintln!(
+            "Removed the rejected bearer token {:?}, a new one will be requested on the next run",
+            settings.token
+        );
+    }
+    result
+}
+
+/// Fetch `kind` tweets for the query on the command line, or for every configured account
+async fn run_fetch(
+    matches: &ArgMatches<'_>,
+    settings: &Settings,
+    kind: QueryKind,
+    query_arg: &str,
+) -> Result<()> {
+    if matches.is_present(query_arg) && matches.is_present("category") {
+        return Err(Error::Usage(
+            "Either fetch a single query or accounts by --category, not both".to_string(),
+        ));
+    }
+
+    let source = get_source(matches, settings).await?;
+    let storage = settings.open_storage()?;
+
+    let max_pages = parse_arg::<u32>(matches, "pages")?;
+    let concurrency = parse_arg::<usize>(matches, "concurrency")?;
+
+    if let Some(query) = matches.value_of(query_arg) {
+        // Fetch from command line arg
+        println!("Fetching {} tweets for {:?}", kind, &query);
+
+        let start = std::time::Instant::now();
+        fetch_for(
+            source.as_ref(),
+            &rate_limit::RateLimitScheduler::new(),
+            storage.as_ref(),
+            kind,
+            query.to_owned(),
+            &SearchOptions::default(),
+            max_pages.unwrap_or(DEFAULT_MAX_PAGES),
+        )
+        .await?;
+        println!(
+            "Time to analyze {}: {} milliseconds",
+            query,
+            (std::time::Instant::now() - start).as_millis()
+        )
+    } else {
+        // Nothing provided on the command line, fetch from configuration
+        let config = Config::get(&settings.accounts)?;
+        // Searches also pick up the timelines of accounts that ask for it
+        let only = match kind {
+            QueryKind::Search => None,
+            kind => Some(kind),
+        };
+        let start = std::time::Instant::now();
+        run_query_from_config(
+            source.as_ref(),
+            storage.as_ref(),
+            config,
+            &categories(matches),
+            max_pages,
+            concurrency,
+            only,
+        )
+        .await?;
+        println!(
+            "Time to analyze accounts from configuration: {} milliseconds",
+            (std::time::Instant::now() - start).as_millis()
+        )
+    }
+    Ok(())
+}
+
+async fn run(matches: &ArgMatches<'_>) -> Result<()> {
+    // Global arguments given after the subcommand only show up in its matches
+    let settings = get_settings(matches.subcommand().1.unwrap_or(matches))?;
     match matches.subcommand() {
         ("analyze", Some(matches)) => {
             let query_to_analyze = matches.value_of("analyze_command");
-            let storage_dir = Path::new(storage::DEFAULT_STORAGE_DIR);
-            let config = AnalysisConfig::new(&std::path::Path::new("conf/analysis.json")).unwrap();
+            let kind = parse_arg::<QueryKind>(matches, "kind")?.unwrap_or_default();
+            let storage = settings.open_storage()?;
+            let storage = storage.as_ref();
+            let config = AnalysisConfig::new(&settings.analysis_config)?;
             let start = std::time::Instant::now();
-            if query_to_analyze.is_some() {
-                let query_to_analyze = query_to_analyze.unwrap();
-                println!(
-                    "Running analysis on queries for \"{}\"...",
-                    query_to_analyze
-                );
-                let result = run_analysis_on_query(config, storage_dir, query_to_analyze).await;
-                if result.is_err() {
-                    eprintln!("Could not run analysis: {}", result.unwrap_err());
-                    exit(1);
+            let categories = categories(matches);
+            let window = TimeWindow::from_args(
+                matches.value_of("since"),
+                matches.value_of("until"),
+                matches.value_of("last"),
+                chrono::Utc::now(),
+            )?;
+            match query_to_analyze {
+                None if matches.is_present("list") => list_analyses(storage)?,
+                None if !categories.is_empty() => {
+                    println!("Running analysis on categories {:?}...", categories);
+                    let accounts = Config::get(&settings.accounts)?;
+                    run_analysis_on_categories(
+                        config,
+                        &accounts,
+                        storage,
+                        &categories,
+                        kind,
+                        window,
+                    )
+                    .await?;
+                }
+                Some(handle) if matches.is_present("compare") => {
+                    println!("Comparing the vocabulary of \"{}\"...", handle);
+                    run_vocabulary_comparison(config, storage, handle, window).await?;
+                }
+                Some(query_to_analyze) => {
+                    println!(
+                        "Running analysis on queries for \"{}\"...",
+                        query_to_analyze
+                    );
+                    run_analysis_on_query(
+                        config,
+                        storage,
+                        query_to_analyze,
+                        parse_arg(matches, "match")?.unwrap_or_default(),
+                        kind,
+                        window,
+                    )
+                    .await?;
                 }
-            } else {
-                println!("Running analysis on all available queries...");
-                let result = run_analysis_with_config(config, storage_dir).await;
-                if result.is_err() {
-                    eprintln!("Could not run analysis: {}", result.unwrap_err());
-                    exit(1);
+                None => {
+                    println!("Running analysis on all available queries...");
+                    run_analysis_with_config(config, storage, kind, window).await?;
                 }
             }
             println!(
@@ -77,55 +595,67 @@ async fn main() {
                 (std::time::Instant::now() - start).as_millis()
             )
         }
-        ("clean", _) => {
-            let res: Result<(), std::io::Error> =
-                util::clear_directory(&Path::new(storage::DEFAULT_STORAGE_DIR));
-            if res.is_err() {
-                eprintln!("Error clearing out storage dir: {:?}", res.unwrap_err());
-                exit(1)
+        ("auth", Some(matches)) => match matches.subcommand() {
+            ("login", _) => auth::login(&settings.consumer_keys, &settings.access_token).await?,
+            (_, _) => {
+                return Err(Error::Usage(
+                    "Missing auth subcommand. Use \"auth --help\" to see them".to_string(),
+                ));
             }
+        },
+        ("watch", Some(matches)) => {
+            invalidate_rejected_token(matches, &settings, run_watch(matches, &settings).await)?
         }
-        ("query", Some(matches)) => {
-            let token_path = matches
-                .value_of("bearer_token")
-                .unwrap_or("auth/bearer.token");
-            let token = auth::get_token(&std::path::Path::new(token_path));
-            if token.is_none() {
-                eprintln!("Could not get the bearer token!");
-                exit(1)
+        ("stream", Some(matches)) => {
+            let config = Config::get(&settings.accounts)?;
+            let token = get_token(matches, &settings).await?;
+            stream::run_stream(
+                &token,
+                settings.open_storage()?.as_ref(),
+                &config,
+                &categories(matches),
+                parse_arg(matches, "bucket")?.unwrap_or(stream::DEFAULT_BUCKET_MINUTES),
+            )
+            .await?
+        }
+        ("accounts", Some(matches)) => run_accounts(matches, &settings).await?,
+        ("config", Some(matches)) => match matches.subcommand() {
+            ("check", _) => check::run_config_check(&settings.accounts, &settings.analysis_config)?,
+            (_, _) => {
+                return Err(Error::Usage(
+                    "Missing config subcommand. Use \"config --help\" to see them".to_string(),
+                ));
             }
-            let token = token.unwrap();
-
-            let maybe_search_query = matches.value_of("search_query");
-            if maybe_search_query.is_some() {
-                // Search from command line arg
-                let search_query = maybe_search_query.unwrap();
-                println!("Searching for {:?}", &search_query);
-
-                let start = std::time::Instant::now();
-                search_for(&token, search_query.to_owned()).await;
-                println!(
-                    "Time to analyze {}: {} milliseconds",
-                    search_query,
-                    (std::time::Instant::now() - start).as_millis()
-                )
-            } else {
-                // No command line search query provided, search from configuration
-                let config = Config::get(&std::path::Path::new("conf/accounts.json"));
-                if config.is_none() {
-                    std::process::exit(1);
-                }
-                let start = std::time::Instant::now();
-                run_query_from_config(&token, config.unwrap()).await;
-                println!(
-                    "Time to analyze accounts from configuration: {} milliseconds",
-                    (std::time::Instant::now() - start).as_millis()
-                )
+        },
+        ("storage", Some(matches)) => match matches.subcommand() {
+            ("migrate", _) => {
+                // Only the files backend has directories to rename
+                let storage = StorageHandler::new().storage_dir(&settings.storage_dir);
+                println!("Moved {} file(s)", storage.migrate()?);
             }
-        }
+            (_, _) => {
+                return Err(Error::Usage(
+                    "Missing storage subcommand. Use \"storage --help\" to see them".to_string(),
+                ));
+            }
+        },
+        ("clean", _) => util::clear_directory(&settings.storage_dir)?,
+        ("query", Some(matches)) => invalidate_rejected_token(
+            matches,
+            &settings,
+            run_fetch(matches, &settings, QueryKind::Search, "search_query").await,
+        )?,
+        ("timeline", Some(matches)) => invalidate_rejected_token(
+            matches,
+            &settings,
+            run_fetch(matches, &settings, QueryKind::Timeline, "handle").await,
+        )?,
         (_, _) => {
-            eprintln!("Could not parse command line. Use \"--help\" to see available commands and subcommands");
-            std::process::exit(1);
+            return Err(Error::Usage(
+                "Could not parse command line. Use \"--help\" to see available commands and subcommands"
+                    .to_string(),
+            ));
         }
     }
+    Ok(())
 }
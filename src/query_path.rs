@@ -0,0 +1,95 @@
+use fnv::FnvHasher;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::{collections::BTreeSet, hash::Hasher};
+
+/// Everything but letters, digits, "@", "_" and "-" is percent encoded. That covers "/", "." and
+/// "%" itself, so a name never leaves its directory and decodes back to the same query
+const QUERY_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC.remove(b'@').remove(b'_').remove(b'-');
+/// Longer names are cut and hashed, most filesystems stop at 255 bytes
+const MAX_DIR_NAME_LEN: usize = 128;
+/// How much of a hashed name stays readable
+const HASHED_NAME_PREFIX_LEN: usize = 64;
+
+pub fn encode_query(query: &str) -> String {
+    utf8_percent_encode(query, QUERY_ENCODE_SET).to_string()
+}
+
+/// Cut `name` and add a hash of all of it after a "~", which encoding never produces. The
+/// original queries are in the directory's metadata
+fn shorten(name: String) -> String {
+    if name.len() <= MAX_DIR_NAME_LEN {
+        return name;
+    }
+    let mut prefix = &name[..HASHED_NAME_PREFIX_LEN];
+    // Don't leave half of a "%XX" behind
+    if let Some(i) = prefix.rfind('%').filter(|i| prefix.len() - i < 3) {
+        prefix = &prefix[..i];
+    }
+    // FNV is fixed, unlike std's hasher, so names stay the same across Rust versions
+    let mut hasher = FnvHasher::default();
+    hasher.write(name.as_bytes());
+    format!("{}~{:016x}", prefix, hasher.finish())
+}
+
+/// Directory name for the results of `query`
+pub fn query_dir_name(query: &str) -> String {
+    shorten(encode_query(query))
+}
+
+/// Directory name for an analysis, `category.$CATEGORY` or the distinct queries joined by "."
+/// in sorted order
+pub fn analysis_dir_name(queries: &[String], category: Option<&str>) -> String {
+    match category {
+        Some(category) => shorten(format!("category.{}", encode_query(category))),
+        None => shorten(
+            queries
+                .iter()
+                .map(|query| encode_query(query))
+                .collect::<BTreeSet<String>>()
+                .into_iter()
+                .collect::<Vec<String>>()
+                .join("."),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{analysis_dir_name, encode_query, query_dir_name, MAX_DIR_NAME_LEN};
+    use percent_encoding::percent_decode_str;
+
+    fn decode_query(name: &str) -> String {
+        percent_decode_str(name).decode_utf8().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn test_encoding_is_reversible() {
+        for query in &["@twitter", "../etc", "a/b", "100%", "#rust lang", "ünïcödé"] {
+            let encoded = encode_query(query);
+            assert!(!encoded.contains('/') && !encoded.contains('.'));
+            assert_eq!(&decode_query(&encoded), query);
+        }
+        assert_eq!(encode_query("@twitter"), "@twitter");
+        assert_eq!(query_dir_name("../etc"), "%2E%2E%2Fetc");
+    }
+
+    #[tokio::test]
+    async fn test_long_names_are_hashed() {
+        let long_query = "a".repeat(300);
+        let name = query_dir_name(&long_query);
+        assert!(name.len() <= MAX_DIR_NAME_LEN);
+        assert!(name.contains('~'));
+        assert_ne!(name, query_dir_name(&"a".repeat(301)));
+
+        // Queries containing "." no longer collide once joined
+        assert_ne!(
+            analysis_dir_name(&["a.b".to_string(), "c".to_string()], None),
+            analysis_dir_name(&["a".to_string(), "b.c".to_string()], None)
+        );
+        // Analyses stored before their queries were made unique
+        assert_eq!(
+            analysis_dir_name(&["b".to_string(), "a".to_string(), "b".to_string()], None),
+            analysis_dir_name(&["a".to_string(), "b".to_string()], None)
+        );
+    }
+}
@@ -0,0 +1,154 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Extra time to wait past the reset timestamp, Twitter's clock and ours don't agree perfectly
+const RESET_GRACE_SECS: i64 = 1;
+/// Shortest wait after a 429, in case the reset it reports is already past for our clock
+const MIN_RATE_LIMIT_BACKOFF_SECS: i64 = 15;
+/// 429s in a row for the same call before giving up on it
+pub const MAX_RATE_LIMIT_ATTEMPTS: u32 = 5;
+
+/// Keeps track of the rate-limit window reported by Twitter and holds off requests once it's used up.
+/// Methods only need `&self` so one scheduler can be shared by every search in a run.
+pub struct RateLimitScheduler {
+    window: Mutex<RateLimitWindow>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct RateLimitWindow {
+    /// Calls allowed per window, unknown until the first response comes back
+    limit: Option<i32>,
+    /// Calls left in the current window
+    remaining: Option<i32>,
+    /// UTC Unix timestamp at which the window resets
+    reset: Option<i64>,
+}
+
+impl RateLimitWindow {
+    /// How long to wait before the next call, if at all
+    fn wait_time(&self, now: i64) -> Option<Duration> {
+        match (self.remaining, self.reset) {
+            (Some(remaining), Some(reset)) if remaining <= 0 && reset > now - RESET_GRACE_SECS => {
+                Some(Duration::from_secs((reset - now + RESET_GRACE_SECS) as u64))
+            }
+            _ => None,
+        }
+    }
+
+    /// Nothing is left until `reset`, but never less than `MIN_RATE_LIMIT_BACKOFF_SECS` from `now`
+    fn exhaust(&mut self, reset: i64, now: i64) {
+        self.remaining = Some(0);
+        self.reset = Some(reset.max(now + MIN_RATE_LIMIT_BACKOFF_SECS - RESET_GRACE_SECS));
+    }
+}
+
+impl RateLimitScheduler {
+    pub fn new() -> RateLimitScheduler {
+        RateLimitScheduler {
+            window: Mutex::new(RateLimitWindow::default()),
+        }
+    }
+
+    /// Sleeps until the window resets if there are no calls left, then reserves a call
+    pub async fn wait_for_budget(&self) {
+        let wait_time = self
+            .window
+            .lock()
+            .unwrap()
+            .wait_time(chrono::Utc::now().timestamp());
+        if let Some(wait_time) = wait_time {
+            println!(
+                "Rate limit exhausted, sleeping {} seconds until the window resets",
+                wait_time.as_secs()
+            );
+            tokio::time::delay_for(wait_time).await;
+        }
+
+        let mut window = self.window.lock().unwrap();
+        if wait_time.is_some() {
+            // Fresh window, the next response will say how big it is
+            window.remaining = None;
+        }
+        if let Some(remaining) = window.remaining.as_mut() {
+            *remaining -= 1;
+        }
+    }
+
+    /// Record the rate-limit status that came back with a response
+    pub fn update(&self, status: &egg_mode::RateLimit) {
+        // egg-mode uses -1 when a header is missing
+        if status.remaining < 0 || status.reset < 0 {
+            return;
+        }
+        let mut window = self.window.lock().unwrap();
+        window.limit = Some(status.limit);
+        window.remaining = Some(status.remaining);
+        window.reset = Some(status.reset as i64);
+        println!(
+            "Rate limit: {}/{} calls left, window resets in {} seconds",
+            status.remaining,
+            status.limit,
+            (status.reset as i64 - chrono::Utc::now().timestamp()).max(0)
+        );
+    }
+
+    /// Twitter refused a call with a 429, nothing is left until `reset`
+    pub fn exhausted_until(&self, reset: i32) {
+        self.window
+            .lock()
+            .unwrap()
+            .exhaust(reset as i64, chrono::Utc::now().timestamp());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RateLimitWindow;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_no_wait_with_budget_left() {
+        let window = RateLimitWindow {
+            limit: Some(450),
+            remaining: Some(10),
+            reset: Some(1000),
+        };
+        assert_eq!(window.wait_time(900), None);
+    }
+
+    #[tokio::test]
+    async fn test_no_wait_when_unknown() {
+        assert_eq!(RateLimitWindow::default().wait_time(900), None);
+    }
+
+    #[tokio::test]
+    async fn test_wait_until_reset() {
+        let window = RateLimitWindow {
+            limit: Some(450),
+            remaining: Some(0),
+            reset: Some(1000),
+        };
+        assert_eq!(window.wait_time(900), Some(Duration::from_secs(101)));
+    }
+
+    #[tokio::test]
+    async fn test_no_wait_after_reset() {
+        let window = RateLimitWindow {
+            limit: Some(450),
+            remaining: Some(0),
+            reset: Some(1000),
+        };
+        assert_eq!(window.wait_time(1010), None);
+    }
+
+    #[tokio::test]
+    // Our clock is ahead of Twitter's, the reported reset is already past
+    async fn test_minimum_wait_after_429() {
+        let mut window = RateLimitWindow::default();
+        window.exhaust(1000, 1010);
+        assert_eq!(window.wait_time(1010), Some(Duration::from_secs(15)));
+
+        window.exhaust(1100, 1010);
+        assert_eq!(window.wait_time(1010), Some(Duration::from_secs(91)));
+    }
+}
@@ -0,0 +1,270 @@
+use crate::error::{Error, Result};
+use crate::source::{SearchPage, SearchRequest, TweetSource};
+use crate::twitter::{Config, QueryKind};
+use async_trait::async_trait;
+use rand::Rng;
+use std::{path::Path, time::Duration};
+
+/// Twitter error codes for "over capacity" and "internal error"
+/// Reference: https://developer.twitter.com/en/support/twitter-api/error-troubleshooting
+const TRANSIENT_ERROR_CODES: [i32; 2] = [130, 131];
+
+/// How often and how patiently failed calls are retried, `"retry"` in `conf/accounts.json`
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt, 0 disables retrying
+    pub max_retries: u32,
+    /// Upper bound of the first wait, doubled on every retry
+    pub initial_backoff_ms: u64,
+    /// Upper bound of any wait
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 60_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The policy of the accounts file at `accounts_path`. The file is optional for plain
+    /// queries so a missing one means the defaults, a broken one is an error
+    pub fn from_accounts(accounts_path: &Path) -> Result<RetryPolicy> {
+        if !accounts_path.exists() {
+            return Ok(RetryPolicy::default());
+        }
+        Ok(Config::get(accounts_path)?.retry.unwrap_or_default())
+    }
+
+    /// Longest wait before retry number `retry`, starting at 0
+    fn max_backoff(&self, retry: u32) -> Duration {
+        let backoff_ms = self
+            .initial_backoff_ms
+            .saturating_mul(2u64.saturating_pow(retry))
+            .min(self.max_backoff_ms);
+        Duration::from_millis(backoff_ms)
+    }
+
+    /// Random wait up to `max_backoff`, so parallel searches don't all retry at once
+    fn backoff(&self, retry: u32) -> Duration {
+        let max_ms = self.max_backoff(retry).as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0, max_ms + 1))
+    }
+}
+
+/// Worth trying again: network trouble and server errors. Rate limits are waited out by the
+/// `RateLimitScheduler` instead, anything else (like a rejected token) won't go away by retrying
+pub fn is_transient(error: &Error) -> bool {
+    use egg_mode::error::Error as EggError;
+    match error {
+        Error::Network(EggError::NetError(_)) | Error::Network(EggError::IOError(_)) => true,
+        Error::Network(EggError::BadStatus(status)) => status.is_server_error(),
+        Error::Network(EggError::TwitterError(_, errors)) => errors
+            .errors
+            .iter()
+            .any(|error| TRANSIENT_ERROR_CODES.contains(&error.code)),
+        _ => false,
+    }
+}
+
+/// Retries transient failures of another source
+pub struct RetryingSource {
+    inner: Box<dyn TweetSource>,
+    policy: RetryPolicy,
+}
+
+impl RetryingSource {
+    pub fn new(inner: Box<dyn TweetSource>, policy: RetryPolicy) -> RetryingSource {
+        RetryingSource { inner, policy }
+    }
+
+    async fn retry(&self, kind: QueryKind, request: &SearchRequest) -> Result<SearchPage> {
+        let mut retry = 0;
+        loop {
+            let result = match kind {
+                QueryKind::Timeline => self.inner.timeline(request).await,
+                _ => self.inner.search(request).await,
+            };
+            match result {
+                Err(e) if is_transient(&e) && retry < self.policy.max_retries => {
+                    let backoff = self.policy.backoff(retry);
+                    retry += 1;
+                    eprintln!(
+                        "{} {:?} failed, retry {}/{} in {} ms: {}",
+                        kind,
+                        request.query,
+                        retry,
+                        self.policy.max_retries,
+                        backoff.as_millis(),
+                        e
+                    );
+                    tokio::time::delay_for(backoff).await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl TweetSource for RetryingSource {
+    async fn search(&self, request: &SearchRequest) -> Result<SearchPage> {
+        self.retry(QueryKind::Search, request).await
+    }
+
+    async fn timeline(&self, request: &SearchRequest) -> Result<SearchPage> {
+        self.retry(QueryKind::Timeline, request).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_transient, RetryPolicy, RetryingSource};
+    use crate::error::{Error, Result};
+    use crate::source::{SearchPage, SearchRequest, TweetSource};
+    use crate::twitter::SearchOptions;
+    use async_trait::async_trait;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+
+    /// Fails with `error` until it has been called `n_failures` times
+    struct FlakySource {
+        n_failures: u32,
+        n_calls: AtomicU32,
+        error: fn() -> Error,
+    }
+
+    #[async_trait]
+    impl TweetSource for FlakySource {
+        async fn search(&self, _: &SearchRequest) -> Result<SearchPage> {
+            if self.n_calls.fetch_add(1, Ordering::SeqCst) < self.n_failures {
+                Err((self.error)())
+            } else {
+                Ok(SearchPage::default())
+            }
+        }
+
+        async fn timeline(&self, request: &SearchRequest) -> Result<SearchPage> {
+            self.search(request).await
+        }
+    }
+
+    fn get_request() -> SearchRequest {
+        SearchRequest {
+            query: "@twitter".to_string(),
+            count: 100,
+            since_id: None,
+            max_id: None,
+            options: SearchOptions::default(),
+        }
+    }
+
+    fn server_error() -> Error {
+        Error::Network(egg_mode::error::Error::BadStatus(
+            hyper::StatusCode::SERVICE_UNAVAILABLE,
+        ))
+    }
+
+    fn unauthorized() -> Error {
+        crate::util::test::get_rejected_token_error()
+    }
+
+    const FAST_POLICY: RetryPolicy = RetryPolicy {
+        max_retries: 2,
+        initial_backoff_ms: 1,
+        max_backoff_ms: 1,
+    };
+
+    #[tokio::test]
+    async fn test_transient_errors() {
+        assert!(is_transient(&server_error()));
+        assert!(!is_transient(&unauthorized()));
+        assert!(!is_transient(&Error::RateLimit(0)));
+    }
+
+    #[tokio::test]
+    async fn test_retries_until_success() {
+        let source = RetryingSource::new(
+            Box::new(FlakySource {
+                n_failures: 2,
+                n_calls: AtomicU32::new(0),
+                error: server_error,
+            }),
+            FAST_POLICY,
+        );
+        assert!(source.search(&get_request()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_gives_up() {
+        let source = RetryingSource::new(
+            Box::new(FlakySource {
+                n_failures: 3,
+                n_calls: AtomicU32::new(0),
+                error: server_error,
+            }),
+            FAST_POLICY,
+        );
+        assert!(matches!(
+            source.search(&get_request()).await,
+            Err(Error::Network(_))
+        ));
+    }
+
+    #[tokio::test]
+    // A rejected token fails on the first attempt
+    async fn test_auth_fails_fast() {
+        let source = RetryingSource::new(
+            Box::new(FlakySource {
+                n_failures: 1,
+                n_calls: AtomicU32::new(0),
+                error: unauthorized,
+            }),
+            FAST_POLICY,
+        );
+        assert!(matches!(
+            source.search(&get_request()).await,
+            Err(Error::Auth(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_policy_from_accounts() {
+        let test_dir = crate::util::test::get_test_dir("test_policy_from_accounts");
+        let accounts_path = test_dir.join("accounts.json");
+        assert_eq!(
+            RetryPolicy::from_accounts(&accounts_path).unwrap(),
+            RetryPolicy::default()
+        );
+        std::fs::write(
+            &accounts_path,
+            r#"{"categories": [], "accounts": [], "retry": {"max_retries": 5}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            RetryPolicy::from_accounts(&accounts_path)
+                .unwrap()
+                .max_retries,
+            5
+        );
+        std::fs::write(&accounts_path, r#"{"categories": [], "#).unwrap();
+        assert!(matches!(
+            RetryPolicy::from_accounts(&accounts_path),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_backoff_bounds() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.max_backoff(0), Duration::from_secs(1));
+        assert_eq!(policy.max_backoff(3), Duration::from_secs(8));
+        assert_eq!(policy.max_backoff(40), Duration::from_secs(60));
+        assert!(policy.backoff(2) <= Duration::from_secs(4));
+    }
+}
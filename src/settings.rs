@@ -0,0 +1,195 @@
+use crate::error::{Error, Result};
+use crate::storage::{open_storage, Storage, StorageBackend, DEFAULT_STORAGE_DIR};
+use crate::twitter::auth;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_ACCOUNTS_PATH: &str = "conf/accounts.json";
+pub const DEFAULT_ANALYSIS_CONFIG_PATH: &str = "conf/analysis.json";
+/// Environment variable pointing at the settings file, same as `--settings`
+pub const SETTINGS_VAR: &str = "TWITTER_ANALYZER_SETTINGS";
+
+/// Where every file the tool reads or writes lives.
+///
+/// Precedence, highest first: command line flags, environment variables, the settings file and
+/// finally the defaults, which are relative to the working directory
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub storage_dir: PathBuf,
+    pub storage_backend: StorageBackend,
+    pub accounts: PathBuf,
+    pub analysis_config: PathBuf,
+    /// Bearer token
+    pub token: PathBuf,
+    pub consumer_keys: PathBuf,
+    pub access_token: PathBuf,
+}
+
+/// One source of settings, anything left out falls through to the next one
+#[derive(serde::Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SettingsLayer {
+    pub storage_dir: Option<PathBuf>,
+    pub storage_backend: Option<StorageBackend>,
+    pub accounts: Option<PathBuf>,
+    pub analysis_config: Option<PathBuf>,
+    pub token: Option<PathBuf>,
+    pub consumer_keys: Option<PathBuf>,
+    pub access_token: Option<PathBuf>,
+}
+
+impl SettingsLayer {
+    /// `TWITTER_ANALYZER_STORAGE_DIR` and friends, looked up through `var`
+    pub fn from_env(var: impl Fn(&str) -> Option<String>) -> Result<SettingsLayer> {
+        let value = |name: &str| {
+            var(&format!("TWITTER_ANALYZER_{}", name)).filter(|value| !value.trim().is_empty())
+        };
+        let path = |name: &str| value(name).map(PathBuf::from);
+        Ok(SettingsLayer {
+            storage_dir: path("STORAGE_DIR"),
+            storage_backend: value("STORAGE_BACKEND")
+                .map(|backend| backend.parse())
+                .transpose()?,
+            accounts: path("ACCOUNTS"),
+            analysis_config: path("ANALYSIS_CONFIG"),
+            token: path("TOKEN"),
+            consumer_keys: path("CONSUMER_KEYS"),
+            access_token: path("ACCESS_TOKEN"),
+        })
+    }
+
+    /// Relative paths in the file are relative to the file itself, so a dataset can be
+    /// moved around with its settings
+    pub fn from_file(settings_path: &Path) -> Result<SettingsLayer> {
+        let json_str = std::fs::read_to_string(settings_path)
+            .map_err(|e| Error::Config(format!("Could not read {:?}: {}", settings_path, e)))?;
+        let layer: SettingsLayer = serde_json::from_str(&json_str)
+            .map_err(|e| Error::Config(format!("Could not parse {:?}: {}", settings_path, e)))?;
+        let base_dir = settings_path.parent().unwrap_or_else(|| Path::new(""));
+        let resolve = |path: Option<PathBuf>| path.map(|path| base_dir.join(path));
+        Ok(SettingsLayer {
+            storage_dir: resolve(layer.storage_dir),
+            storage_backend: layer.storage_backend,
+            accounts: resolve(layer.accounts),
+            analysis_config: resolve(layer.analysis_config),
+            token: resolve(layer.token),
+            consumer_keys: resolve(layer.consumer_keys),
+            access_token: resolve(layer.access_token),
+        })
+    }
+
+    /// Keep what this layer has, take the rest from `lower`
+    pub fn or(self, lower: SettingsLayer) -> SettingsLayer {
+        SettingsLayer {
+            storage_dir: self.storage_dir.or(lower.storage_dir),
+            storage_backend: self.storage_backend.or(lower.storage_backend),
+            accounts: self.accounts.or(lower.accounts),
+            analysis_config: self.analysis_config.or(lower.analysis_config),
+            token: self.token.or(lower.token),
+            consumer_keys: self.consumer_keys.or(lower.consumer_keys),
+            access_token: self.access_token.or(lower.access_token),
+        }
+    }
+}
+
+impl Settings {
+    /// Fill whatever `layer` doesn't have with the defaults
+    pub fn from_layer(layer: SettingsLayer) -> Settings {
+        Settings {
+            storage_dir: layer
+                .storage_dir
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_STORAGE_DIR)),
+            storage_backend: layer.storage_backend.unwrap_or_default(),
+            accounts: layer
+                .accounts
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_ACCOUNTS_PATH)),
+            analysis_config: layer
+                .analysis_config
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_ANALYSIS_CONFIG_PATH)),
+            token: layer
+                .token
+                .unwrap_or_else(|| PathBuf::from(auth::DEFAULT_BEARER_TOKEN_PATH)),
+            consumer_keys: layer
+                .consumer_keys
+                .unwrap_or_else(|| PathBuf::from(auth::DEFAULT_CONSUMER_KEYS_PATH)),
+            access_token: layer
+                .access_token
+                .unwrap_or_else(|| PathBuf::from(auth::DEFAULT_ACCESS_TOKEN_PATH)),
+        }
+    }
+
+    /// Layer the command line over the environment over the settings file, if there is one.
+    /// The settings file itself comes from `settings_path` or `TWITTER_ANALYZER_SETTINGS`
+    pub fn resolve(
+        cli: SettingsLayer,
+        settings_path: Option<&Path>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Settings> {
+        let settings_path = settings_path
+            .map(PathBuf::from)
+            .or_else(|| var(SETTINGS_VAR).map(PathBuf::from));
+        let file = match settings_path {
+            Some(settings_path) => SettingsLayer::from_file(&settings_path)?,
+            None => SettingsLayer::default(),
+        };
+        Ok(Settings::from_layer(
+            cli.or(SettingsLayer::from_env(var)?).or(file),
+        ))
+    }
+
+    /// The storage picked by `storage_backend`, kept in `storage_dir`
+    pub fn open_storage(&self) -> Result<Box<dyn Storage>> {
+        open_storage(self.storage_backend, &self.storage_dir)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Settings, SettingsLayer};
+    use crate::storage::StorageBackend;
+    use crate::util::test::get_test_dir;
+    use std::{collections::BTreeMap, path::PathBuf};
+
+    #[tokio::test]
+    async fn test_precedence() {
+        let test_dir = get_test_dir("test_settings_precedence");
+        let settings_path = test_dir.join("settings.json");
+        std::fs::write(
+            &settings_path,
+            r#"{"storage_dir": "data", "accounts": "accounts.json", "token": "bearer.token"}"#,
+        )
+        .unwrap();
+
+        let env: BTreeMap<String, String> = vec![
+            ("TWITTER_ANALYZER_ACCOUNTS", "env/accounts.json"),
+            ("TWITTER_ANALYZER_TOKEN", "env/bearer.token"),
+            ("TWITTER_ANALYZER_STORAGE_BACKEND", "sqlite"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        let cli = SettingsLayer {
+            token: Some(PathBuf::from("cli/bearer.token")),
+            ..SettingsLayer::default()
+        };
+
+        let settings = Settings::resolve(cli, Some(&settings_path), |name| env.get(name).cloned())
+            .expect("Could not resolve settings");
+        assert_eq!(settings.token, PathBuf::from("cli/bearer.token"));
+        assert_eq!(settings.accounts, PathBuf::from("env/accounts.json"));
+        assert_eq!(settings.storage_backend, StorageBackend::Sqlite);
+        // Relative to the settings file
+        assert_eq!(settings.storage_dir, test_dir.join("data"));
+        assert_eq!(
+            settings.analysis_config,
+            PathBuf::from(super::DEFAULT_ANALYSIS_CONFIG_PATH)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_setting() {
+        let test_dir = get_test_dir("test_settings_unknown");
+        let settings_path = test_dir.join("settings.json");
+        std::fs::write(&settings_path, r#"{"storage": "data"}"#).unwrap();
+        assert!(SettingsLayer::from_file(&settings_path).is_err());
+    }
+}
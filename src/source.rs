@@ -0,0 +1,680 @@
+use crate::error::{Error, Result};
+use crate::storage::{Storage, StorageHandler};
+use crate::twitter::{
+    search_to_tweet_vec, statuses_to_authors, statuses_to_tweet_vec, Author, QueryKind,
+    QueryResult, SearchOptions, Tweet,
+};
+use async_trait::async_trait;
+use chrono::Datelike;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+/// One page worth of search or timeline parameters
+#[derive(Debug, Clone)]
+pub struct SearchRequest {
+    /// Search query, or the handle (with "@") for timelines
+    pub query: String,
+    /// Maximum amount of tweets in the page
+    pub count: u32,
+    /// Only return tweets newer than this id
+    pub since_id: Option<u64>,
+    /// Only return tweets with this id or older
+    pub max_id: Option<u64>,
+    /// Only result type, language, geocode and until are used, `query` has the rest applied
+    pub options: SearchOptions,
+}
+
+/// A page of tweets returned by a `TweetSource`, newest first
+#[derive(Debug, Clone, Default)]
+pub struct SearchPage {
+    pub tweets: Vec<Tweet>,
+    /// Authors of `tweets` by handle, if the source knows them
+    pub authors: BTreeMap<String, Author>,
+    /// Only sources that talk to Twitter have a rate limit
+    pub rate_limit: Option<egg_mode::RateLimit>,
+}
+
+/// Anything that can be searched for tweets
+#[async_trait]
+pub trait TweetSource: Send + Sync {
+    async fn search(&self, request: &SearchRequest) -> Result<SearchPage>;
+
+    /// Tweets posted by the account `request.query`
+    async fn timeline(&self, request: &SearchRequest) -> Result<SearchPage>;
+}
+
+/// Searches the live Twitter API through egg-mode
+pub struct EggModeSource {
+    token: egg_mode::Token,
+}
+
+impl EggModeSource {
+    pub fn new(token: egg_mode::Token) -> EggModeSource {
+        EggModeSource { token }
+    }
+}
+
+#[async_trait]
+impl TweetSource for EggModeSource {
+    async fn search(&self, request: &SearchRequest) -> Result<SearchPage> {
+        let options = &request.options;
+        let mut search = egg_mode::search::search(request.query.clone())
+            .result_type(
+                options
+                    .result_type
+                    .map_or(egg_mode::search::ResultType::Recent, Into::into),
+            )
+            .count(request.count);
+        if let Some(lang) = &options.lang {
+            search = search.lang(lang.clone());
+        }
+        if let Some(geocode) = &options.geocode {
+            let (latitude, longitude, radius) = parse_geocode(geocode)?;
+            search = search.geocode(latitude, longitude, radius);
+        }
+        if let Some(until) = options.until {
+            search = search.until(until.year() as u32, until.month(), until.day());
+        }
+        if let Some(since_id) = request.since_id {
+            search = search.since_tweet(since_id);
+        }
+        if let Some(max_id) = request.max_id {
+            search = search.max_tweet(max_id);
+        }
+        let response = search.call(&self.token).await?;
+        Ok(SearchPage {
+            tweets: search_to_tweet_vec(&response.response),
+            authors: statuses_to_authors(&response.response.statuses),
+            rate_limit: Some(response.rate_limit_status),
+        })
+    }
+
+    async fn timeline(&self, request: &SearchRequest) -> Result<SearchPage> {
+        let screen_name = request.query.trim_start_matches('@').to_string();
+        // Replies and retweets are part of how an account talks too
+        let response = egg_mode::tweet::user_timeline(screen_name, true, true, &self.token)
+            .with_page_size(request.count as i32)
+            .call(request.since_id, request.max_id)
+            .await?;
+        Ok(SearchPage {
+            tweets: statuses_to_tweet_vec(&response.response),
+            authors: statuses_to_authors(&response.response),
+            rate_limit: Some(response.rate_limit_status),
+        })
+    }
+}
+
+/// Parse "latitude,longitude,radius" with the radius in "km" or "mi"
+pub fn parse_geocode(geocode: &str) -> Result<(f32, f32, egg_mode::search::Distance)> {
+    let invalid = || {
+        Error::Config(format!(
+            "Invalid geocode {:?}, expected \"latitude,longitude,radius\" like \"40.44,-79.99,10km\"",
+            geocode
+        ))
+    };
+    let parts: Vec<&str> = geocode.split(',').map(str::trim).collect();
+    let (latitude, longitude, radius) = match parts.as_slice() {
+        [latitude, longitude, radius] => (latitude, longitude, radius),
+        _ => return Err(invalid()),
+    };
+    let latitude = latitude.parse::<f32>().map_err(|_| invalid())?;
+    let longitude = longitude.parse::<f32>().map_err(|_| invalid())?;
+    let radius = if let Some(km) = radius.strip_suffix("km") {
+        egg_mode::search::Distance::Kilometers(km.parse().map_err(|_| invalid())?)
+    } else if let Some(mi) = radius.strip_suffix("mi") {
+        egg_mode::search::Distance::Miles(mi.parse().map_err(|_| invalid())?)
+    } else {
+        return Err(invalid());
+    };
+    Ok((latitude, longitude, radius))
+}
+
+/// Keeps tweets in memory per kind and query, searches only ever match the exact query
+#[derive(Default)]
+pub struct MemorySource {
+    tweets: BTreeMap<(QueryKind, String), Vec<Tweet>>,
+    authors: BTreeMap<String, Author>,
+}
+
+impl MemorySource {
+    pub fn new() -> MemorySource {
+        MemorySource::default()
+    }
+
+    /// Make `tweets` searchable under `query`. Tweets with an id that's already known are skipped
+    pub fn add_tweets(&mut self, kind: QueryKind, query: &str, tweets: Vec<Tweet>) {
+        let known = self.tweets.entry((kind, query.to_string())).or_default();
+        let mut known_ids: BTreeSet<u64> = known.iter().map(|tweet| tweet.id).collect();
+        for tweet in tweets {
+            if tweet.id == 0 || known_ids.insert(tweet.id) {
+                known.push(tweet);
+            }
+        }
+        // Newest first, like Twitter does
+        known.sort_by_key(|tweet| std::cmp::Reverse(tweet.id));
+    }
+
+    /// Make the profiles of authors known, by handle
+    pub fn add_authors(&mut self, authors: BTreeMap<String, Author>) {
+        self.authors.extend(authors);
+    }
+
+    pub fn from_query_results(query_results: Vec<QueryResult>) -> MemorySource {
+        let mut source = MemorySource::new();
+        for query_result in query_results {
+            // Replayed searches are made with the effective query
+            let query = query_result
+                .effective_query
+                .as_ref()
+                .unwrap_or(&query_result.query);
+            source.add_tweets(query_result.kind, query, query_result.tweets);
+            source.add_authors(query_result.authors);
+        }
+        source
+    }
+}
+
+impl MemorySource {
+    fn page(&self, kind: QueryKind, request: &SearchRequest) -> SearchPage {
+        let tweets: Vec<Tweet> = self
+            .tweets
+            .get(&(kind, request.query.clone()))
+            .map(|tweets| {
+                tweets
+                    .iter()
+                    .filter(|tweet| request.since_id.is_none_or(|id| tweet.id > id))
+                    .filter(|tweet| request.max_id.is_none_or(|id| tweet.id <= id))
+                    .take(request.count as usize)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        let authors = tweets
+            .iter()
+            .filter_map(|tweet| {
+                self.authors
+                    .get(&tweet.handle)
+                    .map(|author| (tweet.handle.clone(), author.clone()))
+            })
+            .collect();
+        SearchPage {
+            tweets,
+            authors,
+            rate_limit: None,
+        }
+    }
+}
+
+#[async_trait]
+impl TweetSource for MemorySource {
+    async fn search(&self, request: &SearchRequest) -> Result<SearchPage> {
+        Ok(self.page(QueryKind::Search, request))
+    }
+
+    async fn timeline(&self, request: &SearchRequest) -> Result<SearchPage> {
+        Ok(self.page(QueryKind::Timeline, request))
+    }
+}
+
+/// Replays recorded `query-result.json` files, either a single file or a whole storage directory
+pub struct ReplaySource {
+    recorded: MemorySource,
+}
+
+impl ReplaySource {
+    pub fn from_path(path: &Path) -> Result<ReplaySource> {
+        let query_results = if path.is_dir() {
+            StorageHandler::new()
+                .storage_dir(path)
+                .retrieve_all_query_results()?
+        } else {
+            vec![QueryResult::deserialize(path.to_path_buf())?]
+        };
+        println!(
+            "Replaying {} recorded query result(s) from {:?}",
+            query_results.len(),
+            path
+        );
+        Ok(ReplaySource {
+            recorded: MemorySource::from_query_results(query_results),
+        })
+    }
+}
+
+#[async_trait]
+impl TweetSource for ReplaySource {
+    async fn search(&self, request: &SearchRequest) -> Result<SearchPage> {
+        self.recorded.search(request).await
+    }
+
+    async fn timeline(&self, request: &SearchRequest) -> Result<SearchPage> {
+        self.recorded.timeline(request).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_geocode, MemorySource, SearchPage, SearchRequest, TweetSource};
+    use crate::analysis::{RetweetMode, SearchAnalysis, TimeWindow};
+    use crate::error::{Error, Result};
+    use crate::rate_limit::RateLimitScheduler;
+    use crate::storage::{MemoryStorage, QueryMatch, Storage};
+    use crate::twitter::{
+        auth, fetch_for, run_query_from_config, Config, QueryKind, QueryResult, SearchOptions,
+        Tweet, TwitterAccount,
+    };
+    use crate::util::test;
+    use async_trait::async_trait;
+    use std::{collections::BTreeMap, sync::Mutex};
+
+    /// Remembers the cursors of every request made to a `MemorySource`
+    struct RecordingSource {
+        inner: MemorySource,
+        requests: Mutex<Vec<(Option<u64>, Option<u64>)>>,
+    }
+
+    impl RecordingSource {
+        fn new(inner: MemorySource) -> RecordingSource {
+            RecordingSource {
+                inner,
+                requests: Mutex::new(Vec::new()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl TweetSource for RecordingSource {
+        async fn search(&self, request: &SearchRequest) -> Result<SearchPage> {
+            self.requests
+                .lock()
+                .unwrap()
+                .push((request.since_id, request.max_id));
+            self.inner.search(request).await
+        }
+
+        async fn timeline(&self, request: &SearchRequest) -> Result<SearchPage> {
+            self.inner.timeline(request).await
+        }
+    }
+
+    /// A `MemorySource` that can't be reached after the first page
+    struct FirstPageSource {
+        inner: MemorySource,
+    }
+
+    #[async_trait]
+    impl TweetSource for FirstPageSource {
+        async fn search(&self, request: &SearchRequest) -> Result<SearchPage> {
+            if request.max_id.is_some() {
+                return Err(Error::Network(egg_mode::error::Error::BadUrl));
+            }
+            self.inner.search(request).await
+        }
+
+        async fn timeline(&self, request: &SearchRequest) -> Result<SearchPage> {
+            self.inner.timeline(request).await
+        }
+    }
+
+    /// Every handle in the "news" category
+    fn get_config(handles: &[&str], timeline: bool) -> Config {
+        Config {
+            categories: vec!["news".to_string()],
+            accounts: handles
+                .iter()
+                .map(|handle| TwitterAccount {
+                    handle: handle.to_string(),
+                    category: "news".to_string(),
+                    timeline,
+                    search: SearchOptions::default(),
+                    extra: serde_json::Map::new(),
+                })
+                .collect(),
+            max_pages: None,
+            concurrency: Some(2),
+            keywords: Vec::new(),
+            watch_intervals: BTreeMap::new(),
+            retry: None,
+            extra: serde_json::Map::new(),
+        }
+    }
+
+    fn get_dummy_tweets(ids: std::ops::RangeInclusive<u64>) -> Vec<Tweet> {
+        let template = test::get_dummy_query_result().tweets.remove(0);
+        ids.map(|id| Tweet {
+            id,
+            ..template.clone()
+        })
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn test_memory_source_paging() {
+        let mut source = MemorySource::new();
+        source.add_tweets(QueryKind::Search, "@twitter", get_dummy_tweets(1..=10));
+
+        let page = source
+            .search(&SearchRequest {
+                query: "@twitter".to_string(),
+                count: 3,
+                since_id: Some(2),
+                max_id: Some(8),
+                options: SearchOptions::default(),
+            })
+            .await
+            .unwrap();
+        let ids: Vec<u64> = page.tweets.iter().map(|tweet| tweet.id).collect();
+        assert_eq!(ids, vec![8, 7, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_memory_source_exact_query() {
+        let mut source = MemorySource::new();
+        source.add_tweets(QueryKind::Search, "@twitter", get_dummy_tweets(1..=10));
+
+        let page = source
+            .search(&SearchRequest {
+                query: "@twit".to_string(),
+                count: 100,
+                since_id: None,
+                max_id: None,
+                options: SearchOptions::default(),
+            })
+            .await
+            .unwrap();
+        assert!(page.tweets.is_empty());
+    }
+
+    #[tokio::test]
+    // Pages follow the max_id cursor and later runs stop at the newest stored tweet
+    async fn test_fetch_pagination() {
+        let storage = test::get_test_storage("test_fetch_pagination");
+        let scheduler = RateLimitScheduler::new();
+
+        let mut tweets = MemorySource::new();
+        tweets.add_tweets(QueryKind::Search, "@twitter", get_dummy_tweets(1..=250));
+        let source = RecordingSource::new(tweets);
+        fetch_for(
+            &source,
+            &scheduler,
+            &storage,
+            QueryKind::Search,
+            "@twitter".to_string(),
+            &SearchOptions::default(),
+            2,
+        )
+        .await
+        .expect("Could not run first search");
+        assert_eq!(
+            *source.requests.lock().unwrap(),
+            vec![(None, None), (None, Some(150))]
+        );
+        let stored = storage.retrieve_query_results_for("@twitter").unwrap();
+        let ids: Vec<u64> = stored[0].tweets.iter().map(|tweet| tweet.id).collect();
+        assert_eq!(ids, (51..=250).rev().collect::<Vec<u64>>());
+
+        let mut tweets = MemorySource::new();
+        tweets.add_tweets(QueryKind::Search, "@twitter", get_dummy_tweets(1..=260));
+        let source = RecordingSource::new(tweets);
+        fetch_for(
+            &source,
+            &scheduler,
+            &storage,
+            QueryKind::Search,
+            "@twitter".to_string(),
+            &SearchOptions::default(),
+            5,
+        )
+        .await
+        .expect("Could not run second search");
+        // Reaching the stored tweets shows up as an empty page
+        assert_eq!(
+            *source.requests.lock().unwrap(),
+            vec![(Some(250), None), (Some(250), Some(250))]
+        );
+        let stored = storage.retrieve_query_results_for("@twitter").unwrap();
+        let n_stored: usize = stored.iter().map(|result| result.tweets.len()).sum();
+        assert_eq!(n_stored, 210);
+    }
+
+    #[tokio::test]
+    // Catching up on stored tweets doesn't page past the budget
+    async fn test_fetch_keeps_page_budget() {
+        let storage = test::get_test_storage("test_fetch_keeps_page_budget");
+        let scheduler = RateLimitScheduler::new();
+
+        let mut tweets = MemorySource::new();
+        tweets.add_tweets(QueryKind::Search, "@twitter", get_dummy_tweets(1..=260));
+        storage
+            .save_query(&QueryResult {
+                tweets: get_dummy_tweets(1..=10),
+                ..test::get_dummy_query_result()
+            })
+            .unwrap();
+        let source = RecordingSource::new(tweets);
+        fetch_for(
+            &source,
+            &scheduler,
+            &storage,
+            QueryKind::Search,
+            "@twitter".to_string(),
+            &SearchOptions::default(),
+            1,
+        )
+        .await
+        .expect("Could not search");
+
+        assert_eq!(*source.requests.lock().unwrap(), vec![(Some(10), None)]);
+        let stored = storage.retrieve_query_results_for("@twitter").unwrap();
+        let n_stored: usize = stored.iter().map(|result| result.tweets.len()).sum();
+        assert_eq!(n_stored, 110);
+    }
+
+    #[tokio::test]
+    // Pages fetched before an error are stored anyways
+    async fn test_fetch_failure_keeps_pages() {
+        let storage = MemoryStorage::new();
+        let scheduler = RateLimitScheduler::new();
+
+        let mut inner = MemorySource::new();
+        inner.add_tweets(QueryKind::Search, "@twitter", get_dummy_tweets(1..=250));
+        let source = FirstPageSource { inner };
+        let result = fetch_for(
+            &source,
+            &scheduler,
+            &storage,
+            QueryKind::Search,
+            "@twitter".to_string(),
+            &SearchOptions::default(),
+            5,
+        )
+        .await;
+
+        assert!(matches!(result, Err(Error::Network(_))));
+        let stored = storage.retrieve_query_results_for("@twitter").unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].tweets.len(), 100);
+    }
+
+    #[tokio::test]
+    // One account failing doesn't keep the others from being stored
+    async fn test_run_query_from_config_failure() {
+        let handles = ["@one", "@two", "@three", "@four", "@five"];
+        let mut inner = MemorySource::new();
+        for handle in &handles {
+            inner.add_tweets(QueryKind::Search, handle, get_dummy_tweets(1..=10));
+        }
+        let source = test::FailingSource {
+            inner,
+            failing: (QueryKind::Search, "@three"),
+            error: || Error::Network(egg_mode::error::Error::BadUrl),
+        };
+        let config = get_config(&handles, false);
+        let storage = MemoryStorage::new();
+
+        let result = run_query_from_config(&source, &storage, config, &[], None, None, None).await;
+        assert!(matches!(
+            result,
+            Err(Error::Network(egg_mode::error::Error::BadUrl))
+        ));
+        for handle in &handles {
+            let stored = storage.retrieve_query_results_for(handle).unwrap();
+            assert_eq!(stored.len(), if *handle == "@three" { 0 } else { 1 });
+        }
+    }
+
+    #[tokio::test]
+    // A protected account's timeline only fails that timeline, the token stays cached
+    async fn test_run_query_from_config_refused() {
+        let handles = ["@one", "@protected", "@three"];
+        let mut inner = MemorySource::new();
+        for handle in &handles {
+            inner.add_tweets(QueryKind::Search, handle, get_dummy_tweets(1..=10));
+            inner.add_tweets(QueryKind::Timeline, handle, get_dummy_tweets(1..=10));
+        }
+        let source = test::FailingSource {
+            inner,
+            failing: (QueryKind::Timeline, "@protected"),
+            error: || {
+                Error::from(egg_mode::error::Error::BadStatus(
+                    hyper::StatusCode::UNAUTHORIZED,
+                ))
+            },
+        };
+        let storage = MemoryStorage::new();
+
+        let result = run_query_from_config(
+            &source,
+            &storage,
+            get_config(&handles, true),
+            &[],
+            None,
+            None,
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Refused(_))));
+        for handle in &handles {
+            let stored = storage.retrieve_query_results_for(handle).unwrap();
+            assert_eq!(stored.len(), if *handle == "@protected" { 1 } else { 2 });
+        }
+
+        let test_dir = test::get_test_dir("test_run_query_from_config_refused");
+        let token_path = test_dir.join("bearer.token");
+        let keys_path = test_dir.join("consumer.json");
+        std::fs::write(&token_path, "fakeBearer").unwrap();
+        std::fs::write(&keys_path, r#"{"key": "fakeKey", "secret": "fakeSecret"}"#).unwrap();
+        assert!(!auth::invalidate_rejected_token(&result, &token_path, &keys_path).unwrap());
+        assert!(token_path.exists());
+        let rejected = Err(test::get_rejected_token_error());
+        assert!(auth::invalidate_rejected_token(&rejected, &token_path, &keys_path).unwrap());
+        assert!(!token_path.exists());
+    }
+
+    #[tokio::test]
+    // Query -> store -> analyze without touching the network
+    async fn test_offline_pipeline() {
+        let storage = test::get_test_storage("test_offline_pipeline");
+        let scheduler = RateLimitScheduler::new();
+
+        let mut source = MemorySource::new();
+        source.add_tweets(QueryKind::Search, "@twitter", get_dummy_tweets(1..=250));
+        fetch_for(
+            &source,
+            &scheduler,
+            &storage,
+            QueryKind::Search,
+            "@twitter".to_string(),
+            &SearchOptions::default(),
+            5,
+        )
+        .await
+        .expect("Could not run first search");
+
+        // Second run should only pick up the tweets that are new since the first one
+        source.add_tweets(QueryKind::Search, "@twitter", get_dummy_tweets(251..=260));
+        fetch_for(
+            &source,
+            &scheduler,
+            &storage,
+            QueryKind::Search,
+            "@twitter".to_string(),
+            &SearchOptions::default(),
+            5,
+        )
+        .await
+        .expect("Could not run second search");
+
+        let stored = storage.retrieve_query_results_for("@twitter").unwrap();
+        let n_stored: usize = stored.iter().map(|result| result.tweets.len()).sum();
+        assert_eq!(n_stored, 260);
+
+        let analysis = SearchAnalysis::from_stored_query(
+            &storage,
+            &[],
+            "@twitter",
+            QueryMatch::Exact,
+            QueryKind::Search,
+            RetweetMode::default(),
+            TimeWindow::default(),
+        )
+        .expect("Could not analyze stored queries");
+        assert!(!analysis.word_frequency.is_empty());
+    }
+
+    #[tokio::test]
+    // Timelines are stored next to searches for the same handle without mixing them up
+    async fn test_timeline_kind() {
+        let storage = test::get_test_storage("test_timeline_kind");
+        let scheduler = RateLimitScheduler::new();
+
+        let mut source = MemorySource::new();
+        source.add_tweets(QueryKind::Search, "@twitter", get_dummy_tweets(1..=10));
+        source.add_tweets(QueryKind::Timeline, "@twitter", get_dummy_tweets(1..=3));
+        fetch_for(
+            &source,
+            &scheduler,
+            &storage,
+            QueryKind::Search,
+            "@twitter".to_string(),
+            &SearchOptions::default(),
+            5,
+        )
+        .await
+        .expect("Could not search");
+        fetch_for(
+            &source,
+            &scheduler,
+            &storage,
+            QueryKind::Timeline,
+            "@twitter".to_string(),
+            &SearchOptions::default(),
+            5,
+        )
+        .await
+        .expect("Could not fetch timeline");
+
+        let stored = storage.retrieve_query_results_for("@twitter").unwrap();
+        let n_timeline: usize = stored
+            .iter()
+            .filter(|result| result.kind == QueryKind::Timeline)
+            .map(|result| result.tweets.len())
+            .sum();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(n_timeline, 3);
+    }
+
+    #[tokio::test]
+    async fn test_parse_geocode() {
+        let (latitude, longitude, radius) = parse_geocode("40.44, -79.99, 10km").unwrap();
+        assert_eq!((latitude, longitude), (40.44, -79.99));
+        assert!(matches!(
+            radius,
+            egg_mode::search::Distance::Kilometers(km) if km == 10.0
+        ));
+        assert!(parse_geocode("40.44,-79.99").is_err());
+        assert!(parse_geocode("40.44,-79.99,10 parsecs").is_err());
+    }
+}
@@ -0,0 +1,437 @@
+use crate::analysis::SearchAnalysis;
+use crate::error::{Error, Result};
+use crate::storage::{ensure_has_queries, QueryMatch, Storage};
+use crate::twitter::{QueryKind, QueryResult, Tweet};
+use chrono::{DateTime, SecondsFormat, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{path::Path, sync::Mutex};
+
+/// Name of the database inside the storage dir
+pub const DATABASE_FILENAME: &str = "twitter-analyzer.sqlite3";
+
+/// Tweets are shared between the results that found them, so polling the same query again
+/// only adds rows to `query_result_tweets`. Their retweet and favorite counts change between
+/// polls, so those are kept per result and a stored result reads back as it was fetched. Author
+/// profiles aren't part of the tweets and change too, they're kept per result as well. The full
+/// tweet, author and analysis are kept as JSON next to the columns that get searched on.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS query_results (
+    id INTEGER PRIMARY KEY,
+    query TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    effective_query TEXT,
+    date_utc TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tweets (
+    id INTEGER PRIMARY KEY,
+    handle TEXT NOT NULL,
+    date_utc TEXT NOT NULL,
+    json TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS query_result_tweets (
+    query_result_id INTEGER NOT NULL REFERENCES query_results(id),
+    tweet_id INTEGER NOT NULL REFERENCES tweets(id),
+    position INTEGER NOT NULL,
+    retweet_count INTEGER NOT NULL,
+    favorite_count INTEGER NOT NULL,
+    PRIMARY KEY (query_result_id, tweet_id)
+);
+CREATE TABLE IF NOT EXISTS authors (
+    query_result_id INTEGER NOT NULL REFERENCES query_results(id),
+    handle TEXT NOT NULL,
+    json TEXT NOT NULL,
+    PRIMARY KEY (query_result_id, handle)
+);
+CREATE TABLE IF NOT EXISTS analyses (
+    id INTEGER PRIMARY KEY,
+    category TEXT,
+    kind TEXT NOT NULL,
+    date_utc TEXT NOT NULL,
+    json TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS analysis_queries (
+    analysis_id INTEGER NOT NULL REFERENCES analyses(id),
+    query TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS query_results_by_query ON query_results(query, kind);
+CREATE INDEX IF NOT EXISTS query_results_by_date ON query_results(date_utc);
+CREATE INDEX IF NOT EXISTS tweets_by_handle ON tweets(handle);
+CREATE INDEX IF NOT EXISTS tweets_by_date ON tweets(date_utc);
+CREATE INDEX IF NOT EXISTS query_result_tweets_by_tweet ON query_result_tweets(tweet_id);
+CREATE INDEX IF NOT EXISTS analysis_queries_by_query ON analysis_queries(query);
+";
+
+/// Fixed width, so dates sort as text
+fn format_date(date: &DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn parse_date(date: &str) -> Result<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(date)
+        .map(|date| date.with_timezone(&Utc))
+        .map_err(|e| Error::Parse(format!("Stored date {:?}: {}", date, e)))
+}
+
+/// Everything in one SQLite database
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(database_path: &Path) -> Result<SqliteStorage> {
+        SqliteStorage::from_connection(Connection::open(database_path)?)
+    }
+
+    /// The database in `storage_dir`, created along with the dir if needed
+    pub fn open_in(storage_dir: &Path) -> Result<SqliteStorage> {
+        std::fs::create_dir_all(storage_dir)?;
+        SqliteStorage::open(&storage_dir.join(DATABASE_FILENAME))
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<SqliteStorage> {
+        SqliteStorage::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(connection: Connection) -> Result<SqliteStorage> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteStorage {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Query results matching `filter`, which is appended to the SELECT as is
+    fn load_query_results(
+        &self,
+        filter: &str,
+        params: &[&dyn rusqlite::ToSql],
+    ) -> Result<Vec<QueryResult>> {
+        let connection = self.connection.lock().unwrap();
+        let mut select = connection.prepare(&format!(
+            "SELECT id, query, kind, effective_query, date_utc FROM query_results {} ORDER BY date_utc",
+            filter
+        ))?;
+        let rows = select
+            .query_map(params, |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                    row.get::<_, String>(4)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut select_tweets = connection.prepare(
+            "SELECT tweets.json, query_result_tweets.retweet_count,
+                 query_result_tweets.favorite_count
+             FROM query_result_tweets
+             JOIN tweets ON tweets.id = query_result_tweets.tweet_id
+             WHERE query_result_tweets.query_result_id = ?1
+             ORDER BY query_result_tweets.position",
+        )?;
+        let mut select_authors =
+            connection.prepare("SELECT handle, json FROM authors WHERE query_result_id = ?1")?;
+        let mut query_results = Vec::new();
+        for (id, query, kind, effective_query, date_utc) in rows {
+            let tweets = select_tweets
+                .query_map(params![id], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?))
+                })?
+                .map(|row| {
+                    let (json_str, retweet_count, favorite_count) = row?;
+                    Ok(Tweet {
+                        retweet_count,
+                        favorite_count,
+                        ..serde_json::from_str(&json_str)?
+                    })
+                })
+                .collect::<Result<Vec<Tweet>>>()?;
+            let authors = select_authors
+                .query_map(params![id], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })?
+                .map(|row| {
+                    let (handle, json_str) = row?;
+                    Ok((handle, serde_json::from_str(&json_str)?))
+                })
+                .collect::<Result<_>>()?;
+            query_results.push(QueryResult {
+                query,
+                kind: kind.parse().map_err(Error::Parse)?,
+                effective_query,
+                date_utc: parse_date(&date_utc)?,
+                tweets,
+                authors,
+            });
+        }
+        Ok(query_results)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn save_query(&self, item: &QueryResult) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO query_results (query, kind, effective_query, date_utc) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                item.query,
+                item.kind.to_string(),
+                item.effective_query,
+                format_date(&item.date_utc)
+            ],
+        )?;
+        let query_result_id = transaction.last_insert_rowid();
+        for (position, tweet) in item.tweets.iter().enumerate() {
+            // Stored before ids were tracked, so there's no telling whether two of these are the
+            // same tweet. Each one gets its own negative id, real ones are all positive
+            let tweet_id: i64 = match tweet.id {
+                0 => transaction.query_row(
+                    "SELECT MIN(COALESCE(MIN(id), 0), 0) - 1 FROM tweets",
+                    [],
+                    |row| row.get(0),
+                )?,
+                id => id as i64,
+            };
+            // Other results may point at the stored copy, it's left as it is
+            transaction.execute(
+                "INSERT INTO tweets (id, handle, date_utc, json) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(id) DO NOTHING",
+                params![
+                    tweet_id,
+                    tweet.handle,
+                    format_date(&tweet.date_utc),
+                    serde_json::to_string(tweet)?
+                ],
+            )?;
+            transaction.execute(
+                "INSERT OR IGNORE INTO query_result_tweets
+                     (query_result_id, tweet_id, position, retweet_count, favorite_count)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    query_result_id,
+                    tweet_id,
+                    position as i64,
+                    tweet.retweet_count,
+                    tweet.favorite_count
+                ],
+            )?;
+        }
+        for (handle, author) in &item.authors {
+            transaction.execute(
+                "INSERT INTO authors (query_result_id, handle, json) VALUES (?1, ?2, ?3)",
+                params![query_result_id, handle, serde_json::to_string(author)?],
+            )?;
+        }
+        transaction.commit()?;
+        println!(
+            "Stored query result for {:?} with {} tweet(s)",
+            item.query,
+            item.tweets.len()
+        );
+        Ok(())
+    }
+
+    fn save_analysis(&self, item: &SearchAnalysis) -> Result<()> {
+        ensure_has_queries(item)?;
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO analyses (category, kind, date_utc, json) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                item.category,
+                item.kind.to_string(),
+                format_date(&item.date_utc),
+                serde_json::to_string(item)?
+            ],
+        )?;
+        let analysis_id = transaction.last_insert_rowid();
+        for query in &item.queries {
+            transaction.execute(
+                "INSERT INTO analysis_queries (analysis_id, query) VALUES (?1, ?2)",
+                params![analysis_id, query],
+            )?;
+        }
+        transaction.commit()?;
+        println!("Stored analysis of {:?}", item.queries);
+        Ok(())
+    }
+
+    fn retrieve_query_results_matching(
+        &self,
+        pattern: &str,
+        matching: QueryMatch,
+    ) -> Result<Vec<QueryResult>> {
+        match matching {
+            // Exact lookups go through the index
+            QueryMatch::Exact => self.load_query_results("WHERE query = ?1", &[&pattern]),
+            _ => {
+                let queries: Vec<String> = {
+                    let connection = self.connection.lock().unwrap();
+                    let mut select =
+                        connection.prepare("SELECT DISTINCT query FROM query_results")?;
+                    let queries = select
+                        .query_map([], |row| row.get(0))?
+                        .collect::<rusqlite::Result<Vec<String>>>()?;
+                    queries
+                };
+                let mut query_results = Vec::new();
+                for query in queries
+                    .iter()
+                    .filter(|query| matching.matches(pattern, query))
+                {
+                    query_results.extend(self.load_query_results("WHERE query = ?1", &[query])?);
+                }
+                Ok(query_results)
+            }
+        }
+    }
+
+    fn retrieve_all_query_results(&self) -> Result<Vec<QueryResult>> {
+        self.load_query_results("", &[])
+    }
+
+    fn retrieve_analyses(&self) -> Result<Vec<SearchAnalysis>> {
+        let connection = self.connection.lock().unwrap();
+        let mut select = connection.prepare("SELECT json FROM analyses ORDER BY date_utc")?;
+        let analyses = select
+            .query_map([], |row| row.get::<_, String>(0))?
+            .map(|json_str| Ok(serde_json::from_str(&json_str?)?))
+            .collect::<Result<Vec<SearchAnalysis>>>()?;
+        Ok(analyses)
+    }
+
+    /// Answered by the indexes instead of loading every stored tweet. Tweets without a real id
+    /// are left out like they are for the other backends
+    fn newest_tweet_id_for(&self, query: &str, kind: QueryKind) -> Result<Option<u64>> {
+        let connection = self.connection.lock().unwrap();
+        let newest: Option<i64> = connection
+            .query_row(
+                "SELECT MAX(query_result_tweets.tweet_id) FROM query_result_tweets
+                 JOIN query_results ON query_results.id = query_result_tweets.query_result_id
+                 WHERE query_results.query = ?1 AND query_results.kind = ?2
+                     AND query_result_tweets.tweet_id > 0",
+                params![query, kind.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        Ok(newest.map(|id| id as u64))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SqliteStorage;
+    use crate::storage::{Storage, StorageHandler};
+    use crate::twitter::QueryKind;
+    use crate::util::test::{get_dummy_query_result, get_dummy_search_analysis, get_test_dir};
+
+    #[tokio::test]
+    async fn test_query_roundtrip() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        let mut older = get_dummy_query_result();
+        older.tweets[0].id = 10;
+        let mut newer = get_dummy_query_result();
+        newer.tweets[0].id = 20;
+        newer.tweets[0].retweet_count = 7;
+        storage.save_query(&older).unwrap();
+        storage.save_query(&newer).unwrap();
+
+        let stored = storage.retrieve_query_results_for("@twitter").unwrap();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored[1].tweets[0].retweet_count, 7);
+        // Storing the same tweet again leaves the older result as it was
+        let mut latest = get_dummy_query_result();
+        latest.tweets[0].id = 20;
+        latest.tweets[0].retweet_count = 9;
+        latest.tweets[0].text = "edited".to_string();
+        storage.save_query(&latest).unwrap();
+        let stored = storage.retrieve_query_results_for("@twitter").unwrap();
+        assert_eq!(stored[1].tweets[0].retweet_count, 7);
+        assert_eq!(stored[1].tweets[0].text, newer.tweets[0].text);
+        assert_eq!(stored[2].tweets[0].retweet_count, 9);
+        assert_eq!(stored[1].authors, newer.authors);
+        assert_eq!(stored[1].effective_query, newer.effective_query);
+        assert!(storage
+            .retrieve_query_results_for("@twit")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            storage
+                .newest_tweet_id_for("@twitter", QueryKind::Search)
+                .unwrap(),
+            Some(20)
+        );
+        assert_eq!(
+            storage
+                .newest_tweet_id_for("@twitter", QueryKind::Timeline)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    // Results from before tweet ids were stored, migrated and then copied into the database
+    async fn test_migrate_legacy_tweets() {
+        let test_dir = get_test_dir("test_migrate_legacy_tweets");
+        let legacy_tweet = |text: &str| {
+            format!(
+                r#"{{"text":"{}","handle":"fakeHandle","date_utc":"2020-12-01T00:00:00Z","retweet_count":0,"favorite_count":0}}"#,
+                text
+            )
+        };
+        let legacy_dir = test_dir.join("@twitter");
+        std::fs::create_dir_all(&legacy_dir).unwrap();
+        for (date, texts) in [
+            ("2020-12-01T00:00:00+00:00", vec!["one", "two", "three"]),
+            ("2020-12-02T00:00:00+00:00", vec!["four", "five"]),
+        ] {
+            let tweets: Vec<String> = texts.into_iter().map(legacy_tweet).collect();
+            std::fs::write(
+                legacy_dir.join(format!("{}.query-result.json", date)),
+                format!(
+                    r#"{{"query":"@twitter","date_utc":"{}","tweets":[{}]}}"#,
+                    date,
+                    tweets.join(",")
+                ),
+            )
+            .unwrap();
+        }
+        let files = StorageHandler::new().storage_dir(&test_dir);
+        files.migrate().unwrap();
+
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        for query_result in files.retrieve_all_query_results().unwrap() {
+            storage.save_query(&query_result).unwrap();
+        }
+        let stored = storage.retrieve_query_results_for("@twitter").unwrap();
+        let texts: Vec<&str> = stored
+            .iter()
+            .flat_map(|result| result.tweets.iter().map(|tweet| tweet.text.as_str()))
+            .collect();
+        assert_eq!(texts, vec!["one", "two", "three", "four", "five"]);
+        assert!(stored
+            .iter()
+            .flat_map(|result| &result.tweets)
+            .all(|tweet| tweet.id == 0));
+        assert_eq!(
+            storage
+                .newest_tweet_id_for("@twitter", QueryKind::Search)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_analysis_roundtrip() {
+        let storage = SqliteStorage::open_in_memory().unwrap();
+        let analysis = get_dummy_search_analysis();
+        storage.save_analysis(&analysis).unwrap();
+        let stored = storage.retrieve_analyses().unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].queries, analysis.queries);
+    }
+}
@@ -1,9 +1,211 @@
-use crate::{analysis::SearchAnalysis, twitter::QueryResult};
-use std::{fs, io, io::Write, path::Path, path::PathBuf};
+use crate::error::{Error, Result};
+use crate::query_path;
+use crate::sqlite::SqliteStorage;
+use crate::{
+    analysis::SearchAnalysis,
+    twitter::{QueryKind, QueryResult},
+};
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
 use walkdir::WalkDir;
 
 pub const DEFAULT_STORAGE_DIR: &str = "data";
 
+/// How a stored query is matched against the one asked for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueryMatch {
+    /// Only the query itself, `@nyt` doesn't pick up `@nytimes`
+    #[default]
+    Exact,
+    /// Every query starting with the given one
+    Prefix,
+    /// `*` matches any run of characters, `?` a single one
+    Glob,
+}
+
+impl QueryMatch {
+    pub fn matches(self, pattern: &str, query: &str) -> bool {
+        match self {
+            QueryMatch::Exact => query == pattern,
+            QueryMatch::Prefix => query.starts_with(pattern),
+            QueryMatch::Glob => {
+                let regex = regex::escape(pattern)
+                    .replace("\\*", ".*")
+                    .replace("\\?", ".");
+                regex::Regex::new(&format!("^{}$", regex))
+                    .map(|regex| regex.is_match(query))
+                    .unwrap_or(false)
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for QueryMatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<QueryMatch, String> {
+        match s {
+            "exact" => Ok(QueryMatch::Exact),
+            "prefix" => Ok(QueryMatch::Prefix),
+            "glob" => Ok(QueryMatch::Glob),
+            _ => Err(format!(
+                "Unknown query match {:?}, expected exact, prefix or glob",
+                s
+            )),
+        }
+    }
+}
+
+/// Somewhere to keep query results and analyses
+pub trait Storage: Send + Sync {
+    fn save_query(&self, item: &QueryResult) -> Result<()>;
+
+    fn save_analysis(&self, item: &SearchAnalysis) -> Result<()>;
+
+    /// Retrieve the results of every stored query that `matching` accepts for `pattern`
+    fn retrieve_query_results_matching(
+        &self,
+        pattern: &str,
+        matching: QueryMatch,
+    ) -> Result<Vec<QueryResult>>;
+
+    /// Retrieve any results from a given query. There may be multiple results from different times.
+    fn retrieve_query_results_for(&self, query: &str) -> Result<Vec<QueryResult>> {
+        self.retrieve_query_results_matching(query, QueryMatch::Exact)
+    }
+
+    fn retrieve_all_query_results(&self) -> Result<Vec<QueryResult>>;
+
+    fn retrieve_analyses(&self) -> Result<Vec<SearchAnalysis>>;
+
+    /// Id of the newest tweet stored for exactly `query`, used as the `since_id` of the next search
+    fn newest_tweet_id_for(&self, query: &str, kind: QueryKind) -> Result<Option<u64>> {
+        Ok(self
+            .retrieve_query_results_for(query)?
+            .iter()
+            .filter(|result| result.kind == kind)
+            .filter_map(QueryResult::newest_tweet_id)
+            .max())
+    }
+}
+
+/// Which `Storage` the command line uses, picked with `--storage-backend` or the settings
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// A directory tree of JSON files
+    #[default]
+    Files,
+    /// One SQLite database inside the storage directory
+    Sqlite,
+}
+
+impl std::str::FromStr for StorageBackend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<StorageBackend> {
+        match s {
+            "files" => Ok(StorageBackend::Files),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            _ => Err(Error::Usage(format!(
+                "Unknown storage backend {:?}, expected files or sqlite",
+                s
+            ))),
+        }
+    }
+}
+
+/// Open the `backend` storage kept in `storage_dir`
+pub fn open_storage(backend: StorageBackend, storage_dir: &Path) -> Result<Box<dyn Storage>> {
+    match backend {
+        StorageBackend::Files => Ok(Box::new(StorageHandler::new().storage_dir(storage_dir))),
+        StorageBackend::Sqlite => Ok(Box::new(SqliteStorage::open_in(storage_dir)?)),
+    }
+}
+
+/// Analyses need something to be about
+pub(crate) fn ensure_has_queries(item: &SearchAnalysis) -> Result<()> {
+    if !item.has_queries() {
+        return Err(Error::NoData(
+            "Could not find any queries to analyze".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Keeps everything in memory, nothing survives the process
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStorage {
+    query_results: std::sync::Mutex<Vec<QueryResult>>,
+    analyses: std::sync::Mutex<Vec<SearchAnalysis>>,
+}
+
+#[cfg(test)]
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+}
+
+#[cfg(test)]
+impl Storage for MemoryStorage {
+    fn save_query(&self, item: &QueryResult) -> Result<()> {
+        self.query_results.lock().unwrap().push(item.clone());
+        Ok(())
+    }
+
+    fn save_analysis(&self, item: &SearchAnalysis) -> Result<()> {
+        ensure_has_queries(item)?;
+        self.analyses.lock().unwrap().push(item.clone());
+        Ok(())
+    }
+
+    fn retrieve_query_results_matching(
+        &self,
+        pattern: &str,
+        matching: QueryMatch,
+    ) -> Result<Vec<QueryResult>> {
+        Ok(self
+            .query_results
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|result| matching.matches(pattern, &result.query))
+            .cloned()
+            .collect())
+    }
+
+    fn retrieve_all_query_results(&self) -> Result<Vec<QueryResult>> {
+        Ok(self.query_results.lock().unwrap().clone())
+    }
+
+    fn retrieve_analyses(&self) -> Result<Vec<SearchAnalysis>> {
+        Ok(self.analyses.lock().unwrap().clone())
+    }
+}
+
+/// Stored result files of each query, relative to the storage dir, so lookups don't have to
+/// guess the query from a path. Rebuilt from the results themselves if it goes missing
+#[derive(serde::Serialize, serde::Deserialize, Debug, Default)]
+struct Manifest {
+    queries: BTreeMap<String, Vec<PathBuf>>,
+}
+
+/// What a directory of the storage holds, since long names are hashed
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct DirMetadata {
+    queries: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    category: Option<String>,
+}
+
+/// The original storage, a directory per query with one JSON file per result.
+/// Directory names are the encoded queries, see `query_path`
 pub struct StorageHandler {
     base_dir: PathBuf,
 }
@@ -17,6 +219,9 @@ enum StorageItem {
 impl StorageHandler {
     const QUERY_RESULT_FILENAME: &'static str = "query-result.json";
     const ANALYSIS_RESULT_FILENAME: &'static str = "analysis-result.json";
+    const MANIFEST_FILENAME: &'static str = "manifest.json";
+    const METADATA_FILENAME: &'static str = "metadata.json";
+    const MANIFEST_LOCK_FILENAME: &'static str = "manifest.lock";
 
     pub fn new() -> StorageHandler {
         StorageHandler {
@@ -31,124 +236,287 @@ impl StorageHandler {
         self
     }
 
-    /// Retrieve any results from a given query. There may be multiple results from different times.
-    pub fn retrieve_query_results_for(&self, query: &str) -> io::Result<Vec<QueryResult>> {
-        Ok(WalkDir::new(&self.base_dir)
+    /// Every file under the storage dir whose name ends with `filename`
+    fn stored_files<'a>(&self, filename: &'a str) -> impl Iterator<Item = PathBuf> + 'a {
+        WalkDir::new(&self.base_dir)
             .into_iter()
             // Filter in results that are not errors
-            .filter_map(Result::ok)
-            // Filter in paths that contains the query. Hacky but meh
-            .filter(|entry| entry.path().to_string_lossy().contains(query))
-            // Filter in entries that are .*query.json files
-            .filter(|entry| {
-                entry
-                    .file_name()
-                    .to_string_lossy()
-                    .ends_with(Self::QUERY_RESULT_FILENAME)
-            })
-            // Turn the entries into paths
-            .map(|entry| QueryResult::deserialize(entry.into_path()))
-            // Filter out errors
-            .filter_map(Result::ok)
-            .collect())
+            .filter_map(std::result::Result::ok)
+            .filter(move |entry| entry.file_name().to_string_lossy().ends_with(filename))
+            .map(walkdir::DirEntry::into_path)
     }
 
-    /// Retrieve all queries, (or any) from a given directory
-    pub fn retrieve_all_query_results(&self) -> io::Result<Vec<QueryResult>> {
-        Ok(WalkDir::new(&self.base_dir)
-            .into_iter()
-            // Filter in results that are not errors
-            .filter_map(Result::ok)
-            // Filter in entries that are .*query.json files
-            .filter(|entry| {
-                entry
-                    .file_name()
-                    .to_string_lossy()
-                    .ends_with(Self::QUERY_RESULT_FILENAME)
-            })
-            // Turn the entries into paths
-            .map(|entry| QueryResult::deserialize(entry.into_path()))
-            // Filter out errors
-            .filter_map(Result::ok)
-            .collect())
+    fn manifest_path(&self) -> PathBuf {
+        self.base_dir.join(Self::MANIFEST_FILENAME)
     }
 
-    pub fn save_analysis(&self, item: &SearchAnalysis) -> Result<(), std::io::Error> {
-        if !item.has_queries() {
-            use std::io::{Error, ErrorKind};
-            return Err(Error::new(
-                ErrorKind::Other,
-                "Could not find any queries to analyze",
-            ));
+    /// Held while the manifest is read and written back, released when dropped. A file lock,
+    /// since `watch`, `stream` and `query` can run as separate processes on the same storage dir.
+    /// Waiting for it blocks like the rest of the file I/O here does, it's only ever held for
+    /// one read and write of the manifest
+    fn lock_manifest(&self) -> Result<fs::File> {
+        fs::create_dir_all(&self.base_dir)?;
+        let lock = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.base_dir.join(Self::MANIFEST_LOCK_FILENAME))?;
+        lock.lock()?;
+        Ok(lock)
+    }
+
+    /// The manifest of the storage dir, storage dirs from before there was one get it built
+    /// by reading every stored result once
+    fn load_manifest(&self) -> Result<Manifest> {
+        match fs::read(self.manifest_path()) {
+            Ok(json) => serde_json::from_slice(&json).map_err(|e| {
+                Error::Parse(format!("Could not parse {:?}: {}", self.manifest_path(), e))
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut manifest = Manifest::default();
+                for path in self.stored_files(Self::QUERY_RESULT_FILENAME) {
+                    if let Ok(result) = QueryResult::deserialize(path.clone()) {
+                        let path = path.strip_prefix(&self.base_dir).unwrap_or(&path);
+                        manifest
+                            .queries
+                            .entry(result.query)
+                            .or_default()
+                            .push(path.to_path_buf());
+                    }
+                }
+                if !manifest.queries.is_empty() {
+                    self.save_manifest(&manifest)?;
+                }
+                Ok(manifest)
+            }
+            Err(e) => Err(e.into()),
         }
-        let storage_path = self.create_storage_path(&StorageItem::Analysis(item.clone()));
-        println!("Storing analysis as {:?}", &storage_path);
-        let serialized_item = serde_json::to_string(&item)?;
-        let mut file = fs::File::create(&storage_path)?;
-        file.write_all(serialized_item.as_bytes())?;
+    }
+
+    /// The manifest for looking up results. It's replaced in one rename, so reading it doesn't
+    /// need the lock. Only a missing one that has to be rebuilt and saved takes it
+    fn read_manifest(&self) -> Result<Manifest> {
+        match fs::read(self.manifest_path()) {
+            Ok(json) => serde_json::from_slice(&json).map_err(|e| {
+                Error::Parse(format!("Could not parse {:?}: {}", self.manifest_path(), e))
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                // Nothing to rebuild it from
+                if self
+                    .stored_files(Self::QUERY_RESULT_FILENAME)
+                    .next()
+                    .is_none()
+                {
+                    return Ok(Manifest::default());
+                }
+                let _lock = self.lock_manifest()?;
+                self.load_manifest()
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_manifest(&self, manifest: &Manifest) -> Result<()> {
+        fs::create_dir_all(&self.base_dir)?;
+        // Written next to the manifest and renamed over it, so it's never half written
+        let temp_path = self.manifest_path().with_extension("json.tmp");
+        fs::write(&temp_path, serde_json::to_string_pretty(manifest)?)?;
+        fs::rename(temp_path, self.manifest_path())?;
         Ok(())
     }
 
-    /// Uses ISO 8601 / RFC 3339 date & time format
-    fn create_storage_path(&self, item: &StorageItem) -> PathBuf {
-        // Adjust filename based on type
-        let (query_dir, filename) = match item {
+    /// Directory of `item`, created along with its metadata if needed
+    fn item_dir(&self, item: &StorageItem) -> Result<PathBuf> {
+        let (dir_name, metadata) = match item {
             StorageItem::Analysis(item) => (
-                // Foldername will be `query1.query2.query3` etc
-                PathBuf::from(&item.queries.join(".")),
-                PathBuf::from(format!(
-                    "{}.{}",
-                    &item.date_utc.format("%+").to_string(),
-                    Self::ANALYSIS_RESULT_FILENAME
-                )),
+                query_path::analysis_dir_name(&item.queries, item.category.as_deref()),
+                DirMetadata {
+                    queries: item.queries.clone(),
+                    category: item.category.clone(),
+                },
             ),
             StorageItem::Query(item) => (
-                PathBuf::from(&item.query),
-                PathBuf::from(format!(
-                    "{}.{}",
-                    &item.date_utc.format("%+").to_string(),
-                    Self::QUERY_RESULT_FILENAME
-                )),
+                query_path::query_dir_name(&item.query),
+                DirMetadata {
+                    queries: vec![item.query.clone()],
+                    category: None,
+                },
             ),
         };
-        let item_storage_dir: PathBuf = [&self.base_dir, &query_dir].iter().collect();
-        if !item_storage_dir.exists() {
-            fs::create_dir_all(&item_storage_dir).expect(&format!(
-                "Could not create directory {:?} despite it not being there",
-                &item_storage_dir
-            ));
+        let item_storage_dir = self.base_dir.join(dir_name);
+        let metadata_path = item_storage_dir.join(Self::METADATA_FILENAME);
+        if !metadata_path.exists() {
+            fs::create_dir_all(&item_storage_dir)?;
+            fs::write(metadata_path, serde_json::to_string_pretty(&metadata)?)?;
         }
-        let storage_path: PathBuf = [item_storage_dir, filename].iter().collect();
-        storage_path
+        Ok(item_storage_dir)
     }
 
-    pub fn save_query(&self, item: &QueryResult) -> Result<(), std::io::Error> {
-        let storage_path = self.create_storage_path(&StorageItem::Query(item.clone()));
-        println!("Storing query result as {:?}", &storage_path);
+    /// Uses ISO 8601 / RFC 3339 date & time format
+    fn create_storage_path(&self, item: &StorageItem) -> Result<PathBuf> {
+        // Adjust filename based on type
+        let filename = match item {
+            StorageItem::Analysis(item) => format!(
+                "{}.{}",
+                &item.date_utc.format("%+").to_string(),
+                Self::ANALYSIS_RESULT_FILENAME
+            ),
+            StorageItem::Query(item) => format!(
+                "{}.{}",
+                &item.date_utc.format("%+").to_string(),
+                Self::QUERY_RESULT_FILENAME
+            ),
+        };
+        Ok(self.item_dir(item)?.join(filename))
+    }
+
+    /// Move results stored under raw query names into encoded directories, returns how many
+    /// files were moved. Directories left empty are removed and the manifest is rebuilt
+    pub fn migrate(&self) -> Result<usize> {
+        let _lock = self.lock_manifest()?;
+        let mut n_moved = 0;
+        let query_paths: Vec<PathBuf> = self.stored_files(Self::QUERY_RESULT_FILENAME).collect();
+        let analysis_paths: Vec<PathBuf> =
+            self.stored_files(Self::ANALYSIS_RESULT_FILENAME).collect();
+        let items = query_paths
+            .into_iter()
+            .map(|path| {
+                (
+                    QueryResult::deserialize(path.clone()).map(StorageItem::Query),
+                    path,
+                )
+            })
+            .chain(analysis_paths.into_iter().map(|path| {
+                let analysis = fs::read(&path)
+                    .map_err(Error::from)
+                    .and_then(|json| Ok(serde_json::from_slice(&json)?));
+                (analysis.map(StorageItem::Analysis), path)
+            }));
+        for (item, path) in items {
+            let item = match item {
+                Ok(item) => item,
+                Err(e) => {
+                    eprintln!("Skipping {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            // The file name is kept, only the directory changes
+            let new_path = match path.file_name() {
+                Some(filename) => self.item_dir(&item)?.join(filename),
+                None => continue,
+            };
+            if new_path != path {
+                println!("Moving {:?} to {:?}", path, new_path);
+                fs::rename(&path, &new_path)?;
+                n_moved += 1;
+            }
+        }
+
+        // Deepest first, so nested leftovers of queries with "/" go too
+        for entry in WalkDir::new(&self.base_dir)
+            .min_depth(1)
+            .contents_first(true)
+            .into_iter()
+            .filter_map(std::result::Result::ok)
+            .filter(|entry| entry.file_type().is_dir())
+        {
+            let only_metadata = fs::read_dir(entry.path())?
+                .filter_map(std::result::Result::ok)
+                .all(|child| child.file_name() == Self::METADATA_FILENAME);
+            if only_metadata {
+                let _ = fs::remove_file(entry.path().join(Self::METADATA_FILENAME));
+            }
+            // Fails for directories that still hold something, which is fine
+            let _ = fs::remove_dir(entry.path());
+        }
+        match fs::remove_file(self.manifest_path()) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        self.load_manifest()?;
+        Ok(n_moved)
+    }
+}
+
+impl Storage for StorageHandler {
+    fn retrieve_query_results_matching(
+        &self,
+        pattern: &str,
+        matching: QueryMatch,
+    ) -> Result<Vec<QueryResult>> {
+        Ok(self
+            .read_manifest()?
+            .queries
+            .iter()
+            .filter(|(query, _)| matching.matches(pattern, query))
+            .flat_map(|(_, paths)| paths)
+            .map(|path| QueryResult::deserialize(self.base_dir.join(path)))
+            // Filter out errors, e.g. results that were deleted by hand
+            .filter_map(std::result::Result::ok)
+            .collect())
+    }
+
+    /// Retrieve all queries, (or any) from a given directory
+    fn retrieve_all_query_results(&self) -> Result<Vec<QueryResult>> {
+        Ok(self
+            .stored_files(Self::QUERY_RESULT_FILENAME)
+            .map(QueryResult::deserialize)
+            // Filter out errors
+            .filter_map(std::result::Result::ok)
+            .collect())
+    }
+
+    fn retrieve_analyses(&self) -> Result<Vec<SearchAnalysis>> {
+        Ok(self
+            .stored_files(Self::ANALYSIS_RESULT_FILENAME)
+            .filter_map(|path| fs::read_to_string(path).ok())
+            .filter_map(|json_str| serde_json::from_str(&json_str).ok())
+            .collect())
+    }
+
+    fn save_analysis(&self, item: &SearchAnalysis) -> Result<()> {
+        ensure_has_queries(item)?;
+        let storage_path = self.create_storage_path(&StorageItem::Analysis(item.clone()))?;
+        println!("Storing analysis as {:?}", &storage_path);
         let serialized_item = serde_json::to_string(&item)?;
         let mut file = fs::File::create(&storage_path)?;
         file.write_all(serialized_item.as_bytes())?;
         Ok(())
     }
+
+    fn save_query(&self, item: &QueryResult) -> Result<()> {
+        let storage_path = self.create_storage_path(&StorageItem::Query(item.clone()))?;
+        println!("Storing query result as {:?}", &storage_path);
+        let serialized_item = serde_json::to_string(&item)?;
+        let mut file = fs::File::create(&storage_path)?;
+        file.write_all(serialized_item.as_bytes())?;
+        let relative_path = storage_path
+            .strip_prefix(&self.base_dir)
+            .unwrap_or(&storage_path)
+            .to_path_buf();
+
+        let _lock = self.lock_manifest()?;
+        let mut manifest = self.load_manifest()?;
+        let paths = manifest.queries.entry(item.query.clone()).or_default();
+        // A manifest that was just rebuilt from the stored results already lists it
+        if !paths.contains(&relative_path) {
+            paths.push(relative_path);
+        }
+        self.save_manifest(&manifest)
+    }
 }
 
 #[cfg(test)]
 mod test {
 
-    use super::StorageHandler;
+    use super::{MemoryStorage, QueryMatch, Storage, StorageHandler};
+    use crate::twitter::QueryKind;
     use crate::util::test;
-    use std::path::PathBuf;
-
-    // Create unique storage base dir formatted as "TEST_TEMP_DIR.test_name/"
-    fn get_test_storage_handler(unique_test_name: &str) -> StorageHandler {
-        let test_dir: PathBuf = [test::TEST_TEMP_DIR, unique_test_name].iter().collect();
-        StorageHandler::new().storage_dir(test_dir.as_path())
-    }
 
     #[tokio::test]
     async fn test_analysis_storage() {
         let analysis = test::get_dummy_search_analysis();
-        let storage_handler = get_test_storage_handler("test_analysis_storage");
+        let storage_handler = test::get_test_storage("test_analysis_storage");
         storage_handler
             .save_analysis(&analysis)
             .expect("Could not store analysis!");
@@ -157,7 +525,7 @@ mod test {
     #[tokio::test]
     async fn test_query_storage() {
         let query = test::get_dummy_query_result();
-        let storage_handler = get_test_storage_handler("test_query_storage");
+        let storage_handler = test::get_test_storage("test_query_storage");
         storage_handler
             .save_query(&query)
             .expect("Could not store query!");
@@ -166,7 +534,7 @@ mod test {
     #[tokio::test]
     // Store an empty query twice and ensure that two can be deserialized
     async fn test_query_retrieval() {
-        let storage_handler = get_test_storage_handler("test_query_retrieval");
+        let storage_handler = test::get_test_storage("test_query_retrieval");
 
         let res = storage_handler.save_query(&test::get_dummy_query_result());
         assert!(res.is_ok(), "Could not store query 1: {}", res.unwrap_err());
@@ -175,14 +543,179 @@ mod test {
         assert!(res.is_ok(), "Could not store query 2: {}", res.unwrap_err());
 
         let queries = storage_handler.retrieve_all_query_results();
-        assert!(queries.is_ok(), format!("Error: {:?}", queries.err()));
+        assert!(queries.is_ok(), "Error: {:?}", queries.err());
 
         let queries = queries.unwrap();
         println!("Retrieved queries: {:?}", queries);
-        assert_eq!(
-            queries.is_empty(),
-            false,
+        assert!(
+            !queries.is_empty(),
             "Expected some queries to be retrieved!"
         );
     }
+
+    #[tokio::test]
+    async fn test_newest_tweet_id() {
+        let storage_handler = test::get_test_storage("test_newest_tweet_id");
+
+        let mut older = test::get_dummy_query_result();
+        older.tweets[0].id = 10;
+        let mut newer = test::get_dummy_query_result();
+        newer.tweets[0].id = 20;
+        storage_handler
+            .save_query(&older)
+            .expect("Could not store query 1");
+        storage_handler
+            .save_query(&newer)
+            .expect("Could not store query 2");
+
+        assert_eq!(
+            storage_handler
+                .newest_tweet_id_for("@twitter", QueryKind::Search)
+                .unwrap(),
+            Some(20)
+        );
+        assert_eq!(
+            storage_handler
+                .newest_tweet_id_for("@twitter", QueryKind::Timeline)
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            storage_handler
+                .newest_tweet_id_for("@twit", QueryKind::Search)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    // Exact lookups skip longer queries, like they do for the files
+    async fn test_memory_storage() {
+        let storage = MemoryStorage::new();
+        storage.save_query(&test::get_dummy_query_result()).unwrap();
+        storage
+            .save_analysis(&test::get_dummy_search_analysis())
+            .unwrap();
+        assert_eq!(
+            storage
+                .retrieve_query_results_for("@twitter")
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(storage
+            .retrieve_query_results_for("@twit")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            storage
+                .retrieve_query_results_matching("@twit", QueryMatch::Prefix)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(storage.retrieve_analyses().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_query_match() {
+        assert!(QueryMatch::Exact.matches("@nyt", "@nyt"));
+        assert!(!QueryMatch::Exact.matches("@nyt", "@nytimes"));
+        assert!(QueryMatch::Prefix.matches("@nyt", "@nytimes"));
+        assert!(QueryMatch::Glob.matches("@nyt*", "@nytimes"));
+        assert!(QueryMatch::Glob.matches("@?yt", "@nyt"));
+        assert!(!QueryMatch::Glob.matches("@nyt", "@nytimes"));
+        // Only * and ? are special
+        assert!(!QueryMatch::Glob.matches("@nyt.", "@nyts"));
+    }
+
+    #[tokio::test]
+    // `@nyt` no longer picks up `@nytimes`, and a lost manifest is rebuilt from the results.
+    // Only rebuilding it takes the lock
+    async fn test_exact_query_lookup() {
+        let storage_handler = test::get_test_storage("test_exact_query_lookup");
+        for query in &["@nyt", "@nytimes"] {
+            let mut result = test::get_dummy_query_result();
+            result.query = query.to_string();
+            storage_handler.save_query(&result).unwrap();
+        }
+        let n_results = |pattern: &str, matching: QueryMatch| {
+            storage_handler
+                .retrieve_query_results_matching(pattern, matching)
+                .unwrap()
+                .len()
+        };
+        // Reading doesn't need the lock
+        let lock_path = storage_handler
+            .base_dir
+            .join(StorageHandler::MANIFEST_LOCK_FILENAME);
+        std::fs::remove_file(&lock_path).unwrap();
+        assert_eq!(n_results("@nyt", QueryMatch::Exact), 1);
+        assert_eq!(n_results("@nyt", QueryMatch::Prefix), 2);
+        assert_eq!(n_results("test_exact_query_lookup", QueryMatch::Exact), 0);
+        assert!(!lock_path.exists());
+
+        std::fs::remove_file(storage_handler.manifest_path()).unwrap();
+        assert_eq!(n_results("@nyt", QueryMatch::Exact), 1);
+        assert!(storage_handler.manifest_path().exists());
+    }
+
+    #[tokio::test]
+    // Separate handlers share nothing in memory, like separate processes on one storage dir
+    async fn test_concurrent_manifest_updates() {
+        let storage_handler = test::get_test_storage("test_concurrent_manifest_updates");
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let base_dir = storage_handler.base_dir.clone();
+                std::thread::spawn(move || {
+                    let storage_handler = StorageHandler::new().storage_dir(&base_dir);
+                    for i in 0..5 {
+                        let mut result = test::get_dummy_query_result();
+                        result.query = format!("@writer{}_{}", writer, i);
+                        storage_handler.save_query(&result).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let manifest = storage_handler.load_manifest().unwrap();
+        assert_eq!(manifest.queries.len(), 40);
+    }
+
+    #[tokio::test]
+    // Results stored under the raw query end up in the encoded directory
+    async fn test_migrate() {
+        let storage_handler = test::get_test_storage("test_migrate");
+        let mut result = test::get_dummy_query_result();
+        result.query = "a/b".to_string();
+        let old_dir = storage_handler.base_dir.join("a").join("b");
+        std::fs::create_dir_all(&old_dir).unwrap();
+        std::fs::write(
+            old_dir.join("2020-12-01T00:00:00+00:00.query-result.json"),
+            serde_json::to_string(&result).unwrap(),
+        )
+        .unwrap();
+        storage_handler
+            .save_query(&test::get_dummy_query_result())
+            .unwrap();
+
+        assert_eq!(storage_handler.migrate().unwrap(), 1);
+        assert!(!storage_handler.base_dir.join("a").exists());
+        assert!(storage_handler
+            .base_dir
+            .join("a%2Fb")
+            .join("2020-12-01T00:00:00+00:00.query-result.json")
+            .exists());
+        assert_eq!(
+            storage_handler
+                .retrieve_query_results_for("a/b")
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(storage_handler.migrate().unwrap(), 0);
+    }
 }
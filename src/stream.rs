@@ -0,0 +1,254 @@
+use crate::error::{Error, Result};
+use crate::storage::Storage;
+use crate::twitter::{statuses_to_authors, statuses_to_tweet_vec, Config, QueryKind, QueryResult};
+use crate::watch::shutdown_signals;
+use chrono::{DateTime, TimeZone, Utc};
+use futures::StreamExt;
+use std::time::Duration;
+
+/// Every streamed tweet is stored under this query. It's neither a handle nor a useful search,
+/// `fetch_for` refuses it so searches never end up next to streamed tweets
+pub const STREAM_QUERY: &str = "@@stream";
+/// Minutes of tweets per stored file if the CLI doesn't say otherwise
+pub const DEFAULT_BUCKET_MINUTES: u64 = 10;
+/// Twitter asks to back off exponentially from 5 seconds up to 320 seconds on reconnects
+/// Reference: https://developer.twitter.com/en/docs/twitter-api/v1/tweets/filter-realtime/guides/connecting
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(320);
+/// How often a quiet stream checks whether its bucket is over
+const FLUSH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Maximum for egg-mode's user lookup
+const N_USERS_PER_LOOKUP: usize = 100;
+
+/// Tweets streamed between `starts` and `ends`
+struct Bucket {
+    result: QueryResult,
+    starts: DateTime<Utc>,
+    ends: DateTime<Utc>,
+}
+
+impl Bucket {
+    /// The bucket `now` falls into, buckets start at multiples of their length since the epoch
+    fn containing(now: DateTime<Utc>, minutes: u64) -> Bucket {
+        let length = (minutes.max(1) * 60) as i64;
+        let starts = Utc
+            .timestamp_opt(now.timestamp() - now.timestamp() % length, 0)
+            .single()
+            .unwrap_or(now);
+        Bucket {
+            result: QueryResult::new(STREAM_QUERY, QueryKind::Stream, starts),
+            starts,
+            ends: starts + chrono::Duration::seconds(length),
+        }
+    }
+
+    /// Store the bucket if anything arrived during it. The result is dated `now` like any
+    /// fetched result, so a restart within the same bucket stores a second file next to the
+    /// first one and time windows see it as holding tweets up to `now`
+    fn flush(&mut self, storage: &dyn Storage, now: DateTime<Utc>) -> Result<()> {
+        if self.result.tweets.is_empty() {
+            return Ok(());
+        }
+        println!(
+            "Storing {} streamed tweet(s) from {}",
+            self.result.tweets.len(),
+            self.starts
+        );
+        self.result.date_utc = now;
+        storage.save_query(&self.result)
+    }
+}
+
+fn next_backoff(backoff: Duration) -> Duration {
+    (backoff * 2).min(MAX_BACKOFF)
+}
+
+/// Twitter ids of `handles`, following needs ids instead of handles
+async fn lookup_user_ids(handles: &[String], token: &egg_mode::Token) -> Result<Vec<u64>> {
+    let mut ids = Vec::new();
+    for chunk in handles.chunks(N_USERS_PER_LOOKUP) {
+        let names: Vec<String> = chunk
+            .iter()
+            .map(|handle| handle.trim_start_matches('@').to_string())
+            .collect();
+        let users = egg_mode::user::lookup(names, token).await?;
+        ids.extend(users.response.iter().map(|user| user.id));
+    }
+    Ok(ids)
+}
+
+/// Stream tweets from and mentioning the accounts in `categories` (all if empty) and the
+/// configured keywords, until SIGINT or SIGTERM. Tweets are stored in one file per bucket of
+/// `bucket_minutes`, the bucket in progress is stored on shutdown.
+pub async fn run_stream(
+    token: &egg_mode::Token,
+    storage: &dyn Storage,
+    config: &Config,
+    categories: &[String],
+    bucket_minutes: u64,
+) -> Result<()> {
+    let handles: Vec<String> = config
+        .accounts_in(categories)?
+        .into_iter()
+        .map(|account| account.handle.clone())
+        .collect();
+    // Tracking a handle picks up mentions, following it picks up its own tweets
+    let track: Vec<&String> = handles.iter().chain(config.keywords.iter()).collect();
+    // Twitter would refuse the connection over and over
+    if track.is_empty() {
+        return Err(Error::Config(
+            "Nothing to stream, add accounts or \"keywords\" to the configuration".to_string(),
+        ));
+    }
+    let follow = lookup_user_ids(&handles, token).await?;
+    println!(
+        "Streaming {} account(s) and {} keyword(s)",
+        follow.len(),
+        config.keywords.len()
+    );
+
+    let mut shutdown = shutdown_signals()?;
+    let mut bucket = Bucket::containing(Utc::now(), bucket_minutes);
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        let mut stream = egg_mode::stream::filter()
+            .follow(&follow)
+            .track(&track)
+            .start(token);
+        loop {
+            tokio::select! {
+                message = stream.next() => match message {
+                    Some(Ok(egg_mode::stream::StreamMessage::Tweet(tweet))) => {
+                        // Connected fine, the next drop starts backing off from scratch
+                        backoff = MIN_BACKOFF;
+                        let statuses = [tweet];
+                        bucket.result.tweets.extend(statuses_to_tweet_vec(&statuses));
+                        bucket.result.authors.extend(statuses_to_authors(&statuses));
+                    }
+                    Some(Ok(egg_mode::stream::StreamMessage::Disconnect(code, reason))) => {
+                        eprintln!("Twitter disconnected the stream ({}): {}", code, reason);
+                        break;
+                    }
+                    // Pings, deletes and friends
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => match Error::from(e) {
+                        // Reconnecting won't fix the token or get past a refusal
+                        e @ (Error::Auth(_) | Error::Refused(_)) => {
+                            bucket.flush(storage, Utc::now())?;
+                            return Err(e);
+                        }
+                        e => {
+                            eprintln!("Stream failed: {}", e);
+                            break;
+                        }
+                    },
+                    None => {
+                        eprintln!("Stream ended");
+                        break;
+                    }
+                },
+                _ = tokio::time::delay_for(FLUSH_CHECK_INTERVAL) => {}
+                _ = shutdown.recv() => {
+                    bucket.flush(storage, Utc::now())?;
+                    println!("Stopped streaming");
+                    return Ok(());
+                }
+            }
+            if Utc::now() >= bucket.ends {
+                bucket.flush(storage, Utc::now())?;
+                bucket = Bucket::containing(Utc::now(), bucket_minutes);
+            }
+        }
+
+        println!("Reconnecting in {} seconds", backoff.as_secs());
+        tokio::select! {
+            _ = tokio::time::delay_for(backoff) => {}
+            _ = shutdown.recv() => {
+                bucket.flush(storage, Utc::now())?;
+                println!("Stopped streaming");
+                return Ok(());
+            }
+        }
+        backoff = next_backoff(backoff);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{next_backoff, run_stream, Bucket, MAX_BACKOFF, MIN_BACKOFF, STREAM_QUERY};
+    use crate::error::Error;
+    use crate::rate_limit::RateLimitScheduler;
+    use crate::source::MemorySource;
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::twitter::{fetch_for, Config, QueryKind, SearchOptions};
+    use crate::util::test;
+    use chrono::{TimeZone, Utc};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_bucket_bounds() {
+        let now = Utc.with_ymd_and_hms(2020, 12, 1, 13, 37, 42).unwrap();
+        let bucket = Bucket::containing(now, 10);
+        assert_eq!(
+            bucket.starts,
+            Utc.with_ymd_and_hms(2020, 12, 1, 13, 30, 0).unwrap()
+        );
+        assert_eq!(
+            bucket.ends,
+            Utc.with_ymd_and_hms(2020, 12, 1, 13, 40, 0).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    // Restarting within a bucket stores a second result instead of replacing the first
+    async fn test_flush_same_bucket() {
+        let storage = test::get_test_storage("test_flush_same_bucket");
+        let now = Utc.with_ymd_and_hms(2020, 12, 1, 13, 37, 42).unwrap();
+        for (i, flushed) in [now, now + chrono::Duration::seconds(30)]
+            .iter()
+            .enumerate()
+        {
+            let mut bucket = Bucket::containing(now, 10);
+            bucket.result.tweets = test::get_dummy_query_result().tweets;
+            bucket.result.tweets[0].id = i as u64 + 1;
+            bucket.flush(&storage, *flushed).unwrap();
+        }
+
+        let stored = storage.retrieve_query_results_for(STREAM_QUERY).unwrap();
+        let mut dates: Vec<_> = stored.iter().map(|result| result.date_utc).collect();
+        dates.sort();
+        assert_eq!(dates, vec![now, now + chrono::Duration::seconds(30)]);
+    }
+
+    #[tokio::test]
+    // Fails before connecting when there's nothing to follow or track
+    async fn test_nothing_to_stream() {
+        let config: Config = serde_json::from_str(r#"{"categories": [], "accounts": []}"#).unwrap();
+        let token = egg_mode::Token::Bearer("fakeBearer".to_string());
+        let result = run_stream(&token, &MemoryStorage::new(), &config, &[], 10).await;
+        assert!(matches!(result, Err(Error::Config(_))));
+    }
+
+    #[tokio::test]
+    // Searches can't be stored next to streamed tweets
+    async fn test_stream_query_is_reserved() {
+        let result = fetch_for(
+            &MemorySource::new(),
+            &RateLimitScheduler::new(),
+            &MemoryStorage::new(),
+            QueryKind::Search,
+            STREAM_QUERY.to_string(),
+            &SearchOptions::default(),
+            1,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::Usage(_))));
+    }
+
+    #[tokio::test]
+    async fn test_backoff() {
+        assert_eq!(next_backoff(MIN_BACKOFF), Duration::from_secs(10));
+        assert_eq!(next_backoff(Duration::from_secs(300)), MAX_BACKOFF);
+        assert_eq!(next_backoff(MAX_BACKOFF), MAX_BACKOFF);
+    }
+}
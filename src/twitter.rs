@@ -1,94 +1,623 @@
-use crate::storage::StorageHandler;
-use std::{fs, io, path::PathBuf};
+use crate::error::{Error, Result};
+use crate::rate_limit::{RateLimitScheduler, MAX_RATE_LIMIT_ATTEMPTS};
+use crate::retry::RetryPolicy;
+use crate::source::{SearchPage, SearchRequest, TweetSource};
+use crate::storage::Storage;
+use crate::stream::STREAM_QUERY;
+use futures::{stream, StreamExt};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Tweet {
+    /// Results stored before ids were tracked deserialize with an id of 0
+    #[serde(default)]
+    pub id: u64,
+    /// Full text, for retweets the full text of the retweeted status
     pub text: String,
     pub handle: String,
     pub date_utc: chrono::DateTime<chrono::Utc>,
     // egg_mode uses i32 for these two, might as well mimic it
     pub retweet_count: i32,
     pub favorite_count: i32,
+    // Everything below was added later, older results deserialize with the defaults
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to_status_id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to_user_id: Option<u64>,
+    /// Handle of the user being replied to, without "@"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to_handle: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retweeted_status_id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quoted_status_id: Option<u64>,
+    /// BCP 47 language code as detected by Twitter, "und" if it couldn't tell
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+    /// Name of the client the tweet was posted with, e.g. "Twitter for iPhone"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Without "#"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hashtags: Vec<String>,
+    /// Handles without "@"
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<String>,
+    /// Expanded URLs where Twitter provided them, t.co links otherwise
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub urls: Vec<String>,
+    /// One of "photo", "video" or "animated_gif" per attached media
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub media_types: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_retweet: bool,
+}
+
+impl Tweet {
+    /// Also catches retweets stored before they were flagged, from their "RT @user:" prefix
+    pub fn is_retweet(&self) -> bool {
+        self.is_retweet || self.retweeted_status_id.is_some() || self.text.starts_with("RT @")
+    }
+
+    /// Text without the "RT @user: " prefix and the word cut off by "…" that retweets stored
+    /// before full texts were fetched have. Full texts are returned as they are, even if they end in "…"
+    pub fn full_text(&self) -> &str {
+        let mut text = self.text.as_str();
+        // Only the legacy `text` of a retweet has the prefix, and only that one was truncated
+        if !text.starts_with("RT @") {
+            return text;
+        }
+        if let Some(end) = text.find(": ") {
+            text = &text[end + 2..];
+        }
+        if let Some(truncated) = text.strip_suffix('…') {
+            text = truncated
+                .rfind(char::is_whitespace)
+                .map_or("", |end| &truncated[..end]);
+        }
+        text
+    }
+}
+
+/// Profile of a tweet's author at the time it was fetched
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Author {
+    /// Display name, the handle is the key of the author table
+    pub name: String,
+    /// When the account was created
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// Free form, whatever the user typed in
+    pub location: Option<String>,
+    pub followers_count: i32,
+    pub following_count: i32,
+    pub verified: bool,
+    pub default_profile_image: bool,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct TwitterAccount {
     pub handle: String, // Includes "@"
     pub category: String,
+    /// Also fetch the account's own tweets when running queries from configuration
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub timeline: bool,
+    #[serde(flatten)]
+    pub search: SearchOptions,
+    /// Keys this version doesn't know about, kept so saving doesn't drop them
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// How an account is searched for, all of it is optional
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SearchOptions {
+    /// Recent tweets if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result_type: Option<ResultType>,
+    /// ISO 639-1 language code, e.g. "en"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+    /// "latitude,longitude,radius" with the radius in "km" or "mi", e.g. "40.44,-79.99,10km"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geocode: Option<String>,
+    /// Only tweets from before this date, e.g. "2020-12-01"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<chrono::NaiveDate>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub exclude_retweets: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub exclude_replies: bool,
+    /// Searched for instead of the handle, "{handle}" is replaced with the handle without "@".
+    /// Example: "to:{handle} -filter:retweets"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_template: Option<String>,
+}
+
+impl SearchOptions {
+    /// What is actually sent to Twitter when searching for `handle`
+    pub fn effective_query(&self, handle: &str) -> String {
+        let mut query = match &self.query_template {
+            Some(template) => template.replace("{handle}", handle.trim_start_matches('@')),
+            None => handle.to_string(),
+        };
+        if self.exclude_retweets && !query.contains("-filter:retweets") {
+            query.push_str(" -filter:retweets");
+        }
+        if self.exclude_replies && !query.contains("-filter:replies") {
+            query.push_str(" -filter:replies");
+        }
+        query
+    }
+}
+
+/// Mirrors `egg_mode::search::ResultType`, which can't be deserialized
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultType {
+    Recent,
+    Popular,
+    Mixed,
+}
+
+impl From<ResultType> for egg_mode::search::ResultType {
+    fn from(result_type: ResultType) -> egg_mode::search::ResultType {
+        match result_type {
+            ResultType::Recent => egg_mode::search::ResultType::Recent,
+            ResultType::Popular => egg_mode::search::ResultType::Popular,
+            ResultType::Mixed => egg_mode::search::ResultType::Mixed,
+        }
+    }
+}
+
+/// What a `QueryResult` holds
+#[derive(
+    serde::Serialize,
+    serde::Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryKind {
+    /// Tweets found by searching for the query, i.e. people mentioning an account
+    #[default]
+    Search,
+    /// Tweets posted by the account itself, the query is its handle
+    Timeline,
+    /// Tweets collected live by `stream`, see `crate::stream`
+    Stream,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct QueryResult {
     pub query: String,
+    /// Results stored before timelines were fetched are all searches
+    #[serde(default)]
+    pub kind: QueryKind,
+    /// Query sent to Twitter after applying the account's search options, searches only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effective_query: Option<String>,
     pub date_utc: chrono::DateTime<chrono::Utc>,
     pub tweets: Vec<Tweet>,
+    /// Authors of `tweets` by handle, each author is stored once per result
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub authors: BTreeMap<String, Author>,
+}
+
+/// Maximum for egg-mode's search
+const N_TWEETS_PER_SEARCH_PAGE: u32 = 100;
+/// Maximum for egg-mode's user timeline
+const N_TWEETS_PER_TIMELINE_PAGE: u32 = 200;
+
+impl QueryKind {
+    fn page_size(self) -> u32 {
+        match self {
+            QueryKind::Search | QueryKind::Stream => N_TWEETS_PER_SEARCH_PAGE,
+            QueryKind::Timeline => N_TWEETS_PER_TIMELINE_PAGE,
+        }
+    }
 }
 
-/// Maximum for egg-mode
-const N_TWEETS_PER_PAGE: u32 = 100;
+impl std::fmt::Display for QueryKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            QueryKind::Search => write!(f, "search"),
+            QueryKind::Timeline => write!(f, "timeline"),
+            QueryKind::Stream => write!(f, "stream"),
+        }
+    }
+}
+
+impl std::str::FromStr for QueryKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<QueryKind, String> {
+        match s {
+            "search" => Ok(QueryKind::Search),
+            "timeline" => Ok(QueryKind::Timeline),
+            "stream" => Ok(QueryKind::Stream),
+            _ => Err(format!("Unknown query kind {:?}", s)),
+        }
+    }
+}
+
+/// Amount of pages fetched per query if neither the CLI nor the configuration say otherwise
+pub const DEFAULT_MAX_PAGES: u32 = 5;
+
+/// Amount of searches in flight at once if neither the CLI nor the configuration say otherwise
+pub const DEFAULT_CONCURRENCY: usize = 4;
+
+/// One page for `request`, waiting out the rate limit window and retrying when Twitter
+/// refuses the call anyways
+async fn fetch_page(
+    source: &dyn TweetSource,
+    scheduler: &RateLimitScheduler,
+    kind: QueryKind,
+    request: &SearchRequest,
+) -> Result<SearchPage> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        scheduler.wait_for_budget().await;
+        let response = match kind {
+            QueryKind::Search => source.search(request).await,
+            QueryKind::Timeline => source.timeline(request).await,
+            QueryKind::Stream => Err(Error::Usage(
+                "Streamed tweets can't be fetched page by page, use \"stream\"".to_string(),
+            )),
+        };
+        match response {
+            // Got a 429 anyways, wait out the window and try the same page again
+            Err(Error::RateLimit(reset)) if attempts < MAX_RATE_LIMIT_ATTEMPTS => {
+                scheduler.exhausted_until(reset)
+            }
+            Err(e) => return Err(e),
+            Ok(response) => {
+                if let Some(rate_limit) = &response.rate_limit {
+                    scheduler.update(rate_limit);
+                }
+                return Ok(response);
+            }
+        }
+    }
+}
 
 /// account_handle includes the "@"
-pub async fn search_for(token: &egg_mode::Token, query: String) {
-    let response = egg_mode::search::search(query.clone())
-        .result_type(egg_mode::search::ResultType::Recent)
-        .count(N_TWEETS_PER_PAGE)
-        .call(&token)
-        .await
-        .unwrap()
-        .response;
-
-    let query_result = QueryResult::new(query.as_str(), chrono::Utc::now(), &response);
-    if StorageHandler::new().save_query(&query_result).is_err() {
-        eprintln!("Could not store query!");
-    }
-}
-
-/// Analyze multiple accounts as deserialized from configuration
-pub async fn run_query_from_config(token: &egg_mode::Token, config: crate::twitter::Config) {
-    // Map accounts to analyzation calls
-    let futures: Vec<_> = config
-        .accounts
+/// Follows the `max_id` cursor for up to `max_pages` pages and stores them as a single result.
+/// Only tweets newer than the ones already stored for `query` and `kind` are requested. If
+/// `max_pages` runs out before reaching those, the tweets in between are never fetched and a
+/// warning says so. Pages fetched before an error are stored before the error is returned.
+pub async fn fetch_for(
+    source: &dyn TweetSource,
+    scheduler: &RateLimitScheduler,
+    storage: &dyn Storage,
+    kind: QueryKind,
+    query: String,
+    options: &SearchOptions,
+    max_pages: u32,
+) -> Result<()> {
+    if query == STREAM_QUERY {
+        return Err(Error::Usage(format!(
+            "{:?} is reserved for streamed tweets",
+            query
+        )));
+    }
+    let since_id = storage.newest_tweet_id_for(&query, kind)?;
+    if let Some(since_id) = since_id {
+        println!(
+            "Fetching {} tweets newer than {} for {:?}",
+            kind, since_id, query
+        );
+    }
+
+    let mut query_result = QueryResult::new(query.as_str(), kind, chrono::Utc::now());
+    // Timelines have nothing to filter on
+    let request_query = match kind {
+        QueryKind::Search => {
+            let effective_query = options.effective_query(&query);
+            query_result.effective_query = Some(effective_query.clone());
+            effective_query
+        }
+        QueryKind::Timeline | QueryKind::Stream => query.clone(),
+    };
+    let mut request = SearchRequest {
+        query: request_query,
+        count: kind.page_size(),
+        since_id,
+        max_id: None,
+        options: options.clone(),
+    };
+
+    // Set once there's nothing left to page through
+    let mut reached_end = false;
+    let mut failure = None;
+    for page in 1..=max_pages {
+        let response = match fetch_page(source, scheduler, kind, &request).await {
+            Ok(response) => response,
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        };
+
+        // Twitter can return short pages before the end, only an empty page means we're done
+        let min_id = match response.tweets.iter().map(|tweet| tweet.id).min() {
+            Some(min_id) => min_id,
+            None => {
+                println!(
+                    "Ran out of results for {:?} after {} page(s)",
+                    query,
+                    page - 1
+                );
+                reached_end = true;
+                break;
+            }
+        };
+        query_result.tweets.extend(response.tweets);
+        query_result.authors.extend(response.authors);
+        if min_id == 0 {
+            // Tweets without ids can't be paged through
+            reached_end = true;
+            break;
+        }
+        request.max_id = Some(min_id - 1);
+    }
+
+    if query_result.tweets.is_empty() {
+        println!("No new {} tweets for {:?}, nothing to store", kind, query);
+    } else {
+        if let (false, Some(since_id), Some(max_id)) = (reached_end, since_id, request.max_id) {
+            eprintln!(
+                "Warning: stopped before reaching stored tweet {} for {:?}, {} tweets with ids {} to {} are left unfetched",
+                since_id,
+                query,
+                kind,
+                since_id + 1,
+                max_id
+            );
+        }
+        storage.save_query(&query_result)?;
+    }
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Analyze multiple accounts as deserialized from configuration, only the ones in `categories` if any
+/// `max_pages` and `concurrency` override the values from the configuration.
+/// With `only` set every account is fetched as that kind, otherwise each account is searched for
+/// and has its timeline fetched if it's marked with `"timeline": true`.
+/// Each endpoint shares one rate-limit scheduler so a long list of accounts waits out the window instead of failing.
+/// Every search runs to completion, the first error (if any) is returned afterwards. A rejected
+/// token is returned over any other error, so callers can tell the whole run was refused.
+pub async fn run_query_from_config(
+    source: &dyn TweetSource,
+    storage: &dyn Storage,
+    config: crate::twitter::Config,
+    categories: &[String],
+    max_pages: Option<u32>,
+    concurrency: Option<usize>,
+    only: Option<QueryKind>,
+) -> Result<()> {
+    let max_pages = max_pages.or(config.max_pages).unwrap_or(DEFAULT_MAX_PAGES);
+    // A limit of 0 would never poll anything
+    let concurrency = concurrency
+        .or(config.concurrency)
+        .unwrap_or(DEFAULT_CONCURRENCY)
+        .max(1);
+    // Twitter tracks rate limits per endpoint
+    let search_scheduler = RateLimitScheduler::new();
+    let timeline_scheduler = RateLimitScheduler::new();
+
+    let jobs: Vec<(QueryKind, String, SearchOptions)> = config
+        .accounts_in(categories)?
         .into_iter()
-        .map(|acc| search_for(&token, acc.handle))
+        .flat_map(|acc| {
+            let kinds = match only {
+                Some(kind) => vec![kind],
+                None if acc.timeline => vec![QueryKind::Search, QueryKind::Timeline],
+                None => vec![QueryKind::Search],
+            };
+            kinds
+                .into_iter()
+                .map(move |kind| (kind, acc.handle.clone(), acc.search.clone()))
+        })
         .collect();
 
-    for f in futures {
-        f.await;
+    // A rejected token fails every other job the same way, so the rest is skipped
+    let rejected = AtomicBool::new(false);
+    // Map accounts to analyzation calls, each one stores its result as soon as it's done
+    let results = stream::iter(jobs)
+        .map(|(kind, handle, options)| {
+            let scheduler = match kind {
+                QueryKind::Search | QueryKind::Stream => &search_scheduler,
+                QueryKind::Timeline => &timeline_scheduler,
+            };
+            let rejected = &rejected;
+            async move {
+                let result = if rejected.load(Ordering::SeqCst) {
+                    Err(Error::Auth(
+                        "Skipped after the token was rejected".to_string(),
+                    ))
+                } else {
+                    fetch_for(
+                        source,
+                        scheduler,
+                        storage,
+                        kind,
+                        handle.clone(),
+                        &options,
+                        max_pages,
+                    )
+                    .await
+                };
+                if let Err(Error::Auth(_)) = result {
+                    rejected.store(true, Ordering::SeqCst);
+                }
+                (kind, handle, result)
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect::<Vec<(QueryKind, String, Result<()>)>>()
+        .await;
+
+    let n_jobs = results.len();
+    let failures: Vec<(QueryKind, String, Error)> = results
+        .into_iter()
+        .filter_map(|(kind, handle, result)| result.err().map(|e| (kind, handle, e)))
+        .collect();
+    if failures.is_empty() {
+        return Ok(());
+    }
+    eprintln!("{} of {} queries failed:", failures.len(), n_jobs);
+    for (kind, handle, e) in &failures {
+        eprintln!("  {} {}: {}", kind, handle, e);
     }
+    let first = failures
+        .iter()
+        .position(|(_, _, e)| matches!(e, Error::Auth(_)))
+        .unwrap_or(0);
+    Err(failures.into_iter().nth(first).unwrap().2)
 }
 
 /// Parse an egg_mode::search::SearchResult into a serializable vector of tweets
 pub fn search_to_tweet_vec(search: &egg_mode::search::SearchResult) -> Vec<Tweet> {
+    statuses_to_tweet_vec(&search.statuses)
+}
+
+/// Parse egg_mode tweets, e.g. from a timeline, into a serializable vector of tweets
+pub fn statuses_to_tweet_vec(statuses: &[egg_mode::tweet::Tweet]) -> Vec<Tweet> {
     let mut tweets = Vec::new();
-    for tweet in &search.statuses {
+    for tweet in statuses {
         // TODO Clean this up, it's super weird
-        let temp = &*(tweet.user.as_ref().unwrap());
+        let temp = tweet.user.as_ref().unwrap();
         let handle = temp.screen_name.clone();
+        // Retweets are truncated to fit "RT @user: " in, the retweeted status has it all.
+        // egg-mode always asks for extended tweets, so `text` is never cut at 140 characters
+        let text = match &tweet.retweeted_status {
+            Some(status) => status.text.to_owned(),
+            None => tweet.text.to_owned(),
+        };
+        let entities = &tweet.entities;
+        // Extended entities hold every attached media, the regular ones only the first
+        let media = match &tweet.extended_entities {
+            Some(extended) => extended.media.as_slice(),
+            None => entities.media.as_deref().unwrap_or_default(),
+        };
         tweets.push(Tweet {
+            id: tweet.id,
             handle,
-            text: tweet.text.to_owned(),
+            text,
             date_utc: tweet.created_at,
             retweet_count: tweet.retweet_count,
             favorite_count: tweet.favorite_count,
+            in_reply_to_status_id: tweet.in_reply_to_status_id,
+            in_reply_to_user_id: tweet.in_reply_to_user_id,
+            in_reply_to_handle: tweet.in_reply_to_screen_name.clone(),
+            retweeted_status_id: tweet.retweeted_status.as_ref().map(|status| status.id),
+            quoted_status_id: tweet.quoted_status_id,
+            lang: tweet.lang.clone(),
+            source: tweet.source.as_ref().map(|source| source.name.clone()),
+            hashtags: entities
+                .hashtags
+                .iter()
+                .map(|hashtag| hashtag.text.clone())
+                .collect(),
+            mentions: entities
+                .user_mentions
+                .iter()
+                .map(|mention| mention.screen_name.clone())
+                .collect(),
+            urls: entities
+                .urls
+                .iter()
+                .map(|url| url.expanded_url.clone().unwrap_or_else(|| url.url.clone()))
+                .collect(),
+            media_types: media
+                .iter()
+                .map(|media| media_type_name(media.media_type).to_string())
+                .collect(),
+            is_retweet: tweet.retweeted_status.is_some(),
         })
     }
     tweets
 }
 
+/// Same names Twitter uses in its API
+fn media_type_name(media_type: egg_mode::entities::MediaType) -> &'static str {
+    match media_type {
+        egg_mode::entities::MediaType::Photo => "photo",
+        egg_mode::entities::MediaType::Video => "video",
+        egg_mode::entities::MediaType::Gif => "animated_gif",
+    }
+}
+
+/// Collect the authors of egg_mode tweets into a table keyed by handle
+pub fn statuses_to_authors(statuses: &[egg_mode::tweet::Tweet]) -> BTreeMap<String, Author> {
+    statuses
+        .iter()
+        .filter_map(|tweet| tweet.user.as_ref())
+        .map(|user| {
+            (
+                user.screen_name.clone(),
+                Author {
+                    name: user.name.clone(),
+                    created_at: user.created_at,
+                    location: user.location.clone(),
+                    followers_count: user.followers_count,
+                    following_count: user.friends_count,
+                    verified: user.verified,
+                    default_profile_image: user.default_profile_image,
+                },
+            )
+        })
+        .collect()
+}
+
 impl QueryResult {
     pub fn new(
         query: &str,
+        kind: QueryKind,
         date_utc: chrono::DateTime<chrono::Utc>,
-        search: &egg_mode::search::SearchResult,
     ) -> QueryResult {
         QueryResult {
             query: query.to_string(),
+            kind,
+            effective_query: None,
             date_utc,
-            tweets: search_to_tweet_vec(&search),
+            tweets: Vec::new(),
+            authors: BTreeMap::new(),
         }
     }
 
-    pub fn deserialize(path: PathBuf) -> Result<QueryResult, io::Error> {
+    /// Id of the most recent tweet in this result, if there are any tweets with ids
+    pub fn newest_tweet_id(&self) -> Option<u64> {
+        self.tweets
+            .iter()
+            .map(|tweet| tweet.id)
+            .filter(|id| *id != 0)
+            .max()
+    }
+
+    /// Drops the authors that no longer have a tweet in this result, e.g. after filtering tweets
+    pub fn prune_authors(&mut self) {
+        let tweets = &self.tweets;
+        self.authors
+            .retain(|handle, _| tweets.iter().any(|tweet| &tweet.handle == handle));
+    }
+
+    pub fn deserialize(path: PathBuf) -> Result<QueryResult> {
         Ok(serde_json::from_slice(&fs::read(path)?)?)
     }
 }
@@ -105,56 +634,420 @@ fn _print_tweets(search_result: &egg_mode::search::SearchResult) {
 }
 
 pub mod auth {
+    use crate::error::{Error, Result};
+    use std::{io::Write, path::Path};
+
+    pub const DEFAULT_BEARER_TOKEN_PATH: &str = "auth/bearer.token";
+    /// `{"key": "...", "secret": "..."}`
+    pub const DEFAULT_CONSUMER_KEYS_PATH: &str = "auth/consumer.json";
+    /// Written by `auth login`
+    pub const DEFAULT_ACCESS_TOKEN_PATH: &str = "auth/access.json";
+    const CONSUMER_KEY_VAR: &str = "TWITTER_CONSUMER_KEY";
+    const CONSUMER_SECRET_VAR: &str = "TWITTER_CONSUMER_SECRET";
+
+    /// Which token a run authenticates with
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub enum TokenKind {
+        /// App-only, see `get_or_request_token`
+        #[default]
+        Bearer,
+        /// User context, see `login`
+        Access,
+    }
+
+    impl std::str::FromStr for TokenKind {
+        type Err = String;
+
+        fn from_str(s: &str) -> std::result::Result<TokenKind, String> {
+            match s {
+                "bearer" => Ok(TokenKind::Bearer),
+                "access" => Ok(TokenKind::Access),
+                _ => Err(format!("Unknown token kind {:?}", s)),
+            }
+        }
+    }
+
     /// Reads token string from `token_path` and trims whitespace
-    pub fn get_token(token_path: &std::path::Path) -> Option<egg_mode::Token> {
-        let token_str = std::fs::read_to_string(token_path);
-        if token_str.is_err() {
-            eprintln!("Could not read {:?}", token_path);
+    pub fn get_token(token_path: &Path) -> Result<egg_mode::Token> {
+        let token_str = std::fs::read_to_string(token_path)
+            .map_err(|e| Error::Auth(format!("Could not read {:?}: {}", token_path, e)))?;
+        let token_str = token_str.trim().to_string();
+        if token_str.is_empty() {
+            return Err(Error::Auth(format!("{:?} is empty", token_path)));
+        }
+        Ok(egg_mode::auth::Token::Bearer(token_str))
+    }
+
+    /// Consumer key and secret from `TWITTER_CONSUMER_KEY` and `TWITTER_CONSUMER_SECRET`, if both are set
+    pub fn consumer_keys_from_env() -> Option<egg_mode::KeyPair> {
+        let key = std::env::var(CONSUMER_KEY_VAR).ok()?;
+        let secret = std::env::var(CONSUMER_SECRET_VAR).ok()?;
+        if key.trim().is_empty() || secret.trim().is_empty() {
             return None;
         }
-        let token_str = token_str.unwrap().trim().to_string();
-        Some(egg_mode::auth::Token::Bearer(token_str))
+        Some(egg_mode::KeyPair::new(
+            key.trim().to_string(),
+            secret.trim().to_string(),
+        ))
+    }
+
+    /// Consumer key and secret stored in `keys_path`, None if there is no such file
+    pub fn consumer_keys_from_file(keys_path: &Path) -> Result<Option<egg_mode::KeyPair>> {
+        if !keys_path.exists() {
+            return Ok(None);
+        }
+        let json_str = std::fs::read_to_string(keys_path)
+            .map_err(|e| Error::Auth(format!("Could not read {:?}: {}", keys_path, e)))?;
+        serde_json::from_str(&json_str)
+            .map(Some)
+            .map_err(|e| Error::Auth(format!("Could not parse {:?}: {}", keys_path, e)))
+    }
+
+    /// The environment wins over the file
+    pub fn get_consumer_keys(keys_path: &Path) -> Result<Option<egg_mode::KeyPair>> {
+        match consumer_keys_from_env() {
+            Some(keys) => Ok(Some(keys)),
+            None => consumer_keys_from_file(keys_path),
+        }
+    }
+
+    /// Write a token or key to `path`, readable by the current user only
+    pub fn write_secret(path: &Path, contents: &str) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+            // The mode only applies to new files
+            if path.exists() {
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+            }
+        }
+        options.open(path)?.write_all(contents.as_bytes())?;
+        Ok(())
+    }
+
+    /// Bearer token cached in `token_path`. Without one, the consumer keys are exchanged for a
+    /// new token which is cached for the next runs
+    pub async fn get_or_request_token(
+        token_path: &Path,
+        keys_path: &Path,
+    ) -> Result<egg_mode::Token> {
+        if token_path.exists() {
+            return get_token(token_path);
+        }
+        let keys = get_consumer_keys(keys_path)?.ok_or_else(|| {
+            Error::Auth(format!(
+                "No bearer token in {:?} and no consumer key and secret in {:?} or ${} and ${}",
+                token_path, keys_path, CONSUMER_KEY_VAR, CONSUMER_SECRET_VAR
+            ))
+        })?;
+        println!("Requesting a bearer token with the consumer key and secret...");
+        let token = egg_mode::auth::bearer_token(&keys)
+            .await
+            .map_err(rejected_keys)?;
+        if let egg_mode::Token::Bearer(bearer) = &token {
+            write_secret(token_path, bearer)?;
+            println!("Cached the bearer token in {:?}", token_path);
+        }
+        Ok(token)
+    }
+
+    /// Access token saved by `login`
+    pub fn get_access_token(access_path: &Path) -> Result<egg_mode::Token> {
+        let json_str = std::fs::read_to_string(access_path).map_err(|e| {
+            Error::Auth(format!(
+                "Could not read {:?}, log in with \"auth login\" first: {}",
+                access_path, e
+            ))
+        })?;
+        let token: egg_mode::Token = serde_json::from_str(&json_str)
+            .map_err(|e| Error::Auth(format!("Could not parse {:?}: {}", access_path, e)))?;
+        match token {
+            egg_mode::Token::Access { .. } => Ok(token),
+            egg_mode::Token::Bearer(_) => Err(Error::Auth(format!(
+                "{:?} holds a bearer token instead of an access token",
+                access_path
+            ))),
+        }
+    }
+
+    /// PIN based OAuth 1.0a login, the access token is saved to `access_path`
+    pub async fn login(keys_path: &Path, access_path: &Path) -> Result<()> {
+        let keys = get_consumer_keys(keys_path)?.ok_or_else(|| {
+            Error::Auth(format!(
+                "Logging in needs a consumer key and secret in {:?} or ${} and ${}",
+                keys_path, CONSUMER_KEY_VAR, CONSUMER_SECRET_VAR
+            ))
+        })?;
+        // "oob" makes Twitter show a PIN instead of redirecting
+        let request_token = egg_mode::auth::request_token(&keys, "oob")
+            .await
+            .map_err(rejected_keys)?;
+        println!(
+            "Open {} in a browser, authorize the app and enter the PIN here:",
+            egg_mode::auth::authorize_url(&request_token)
+        );
+        let mut pin = String::new();
+        std::io::stdin().read_line(&mut pin)?;
+        let (token, _, screen_name) =
+            egg_mode::auth::access_token(keys, &request_token, pin.trim())
+                .await
+                .map_err(rejected_keys)?;
+
+        write_secret(access_path, &serde_json::to_string_pretty(&token)?)?;
+        println!(
+            "Logged in as @{}, saved the access token in {:?}",
+            screen_name, access_path
+        );
+        Ok(())
+    }
+
+    /// Calls that hand out tokens are only ever refused because of the keys they were given
+    fn rejected_keys(e: egg_mode::error::Error) -> Error {
+        match Error::from(e) {
+            Error::Refused(msg) => Error::Auth(msg),
+            e => e,
+        }
+    }
+
+    /// Removes the cached token after Twitter rejected it, so the next run requests a new one.
+    /// Tokens that can't be requested again are left alone. True if the token was removed
+    pub fn invalidate_cached_token(token_path: &Path, keys_path: &Path) -> Result<bool> {
+        if !token_path.exists() || get_consumer_keys(keys_path)?.is_none() {
+            return Ok(false);
+        }
+        std::fs::remove_file(token_path)?;
+        Ok(true)
+    }
+
+    /// `invalidate_cached_token` if `result` failed because of the token. Twitter refusing the
+    /// tweets of a single query leaves it alone
+    pub fn invalidate_rejected_token(
+        result: &Result<()>,
+        token_path: &Path,
+        keys_path: &Path,
+    ) -> Result<bool> {
+        match result {
+            Err(Error::Auth(_)) => invalidate_cached_token(token_path, keys_path),
+            _ => Ok(false),
+        }
     }
 }
 
 #[tokio::test]
+#[ignore = "needs auth/bearer.token and network access, run with --ignored"]
 async fn test_authentication() {
-    let maybe_token = auth::get_token(std::path::Path::new("auth/bearer.token"));
-    assert!(maybe_token.is_some());
+    let maybe_token = auth::get_token(std::path::Path::new(auth::DEFAULT_BEARER_TOKEN_PATH));
+    assert!(maybe_token.is_ok());
     let token = maybe_token.unwrap();
     let user = egg_mode::user::show("twitter", &token).await;
     assert!(user.is_ok());
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Config {
     pub categories: Vec<String>,
     pub accounts: Vec<TwitterAccount>,
+    /// Pages of search results to fetch per query, each page holds up to 100 tweets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_pages: Option<u32>,
+    /// Maximum amount of searches running at the same time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<usize>,
+    /// Tracked by `stream` next to the handles
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keywords: Vec<String>,
+    /// Minutes between fetches of each category in `watch`, the others use `--interval`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub watch_intervals: BTreeMap<String, u64>,
+    /// Retries of failed Twitter calls, the defaults of `RetryPolicy` if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+    /// Keys this version doesn't know about, kept so saving doesn't drop them
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Config {
-    pub fn get(config_path: &std::path::Path) -> Option<Config> {
-        let json_str = std::fs::read_to_string(config_path);
-        if json_str.is_err() {
-            eprintln!("Could not read {:?}", config_path);
-            return None;
-        }
-        let maybe_json: serde_json::Result<Config> =
-            serde_json::from_str(json_str.unwrap().as_str());
-        if maybe_json.is_err() {
-            eprintln!("serde_json parse error");
-            return None;
-        }
+    pub fn get(config_path: &std::path::Path) -> Result<Config> {
+        let json_str = std::fs::read_to_string(config_path)
+            .map_err(|e| Error::Config(format!("Could not read {:?}: {}", config_path, e)))?;
+        serde_json::from_str(json_str.as_str())
+            .map_err(|e| Error::Config(format!("Could not parse {:?}: {}", config_path, e)))
+    }
+
+    /// Same layout as the file in the repo, so diffs only show what actually changed.
+    /// Unknown keys are written after the known ones
+    pub fn save(&self, config_path: &std::path::Path) -> Result<()> {
+        let json_str = serde_json::to_string_pretty(self)?;
+        fs::write(config_path, json_str)?;
+        Ok(())
+    }
 
-        Some(maybe_json.unwrap())
+    /// Accounts in any of `categories`, every account if there are none.
+    /// Each category needs at least one account, so typos don't silently fetch nothing
+    pub fn accounts_in(&self, categories: &[String]) -> Result<Vec<&TwitterAccount>> {
+        if let Some(category) = categories.iter().find(|category| {
+            !self
+                .accounts
+                .iter()
+                .any(|account| &account.category == *category)
+        }) {
+            return Err(Error::Usage(format!(
+                "No accounts in category {:?}, categories are {:?}",
+                category, self.categories
+            )));
+        }
+        Ok(self
+            .accounts
+            .iter()
+            .filter(|account| categories.is_empty() || categories.contains(&account.category))
+            .collect())
     }
 }
 
+#[tokio::test]
+async fn test_accounts_in_category() {
+    let config = Config::get(std::path::Path::new("conf/accounts.json")).unwrap();
+    let news = config.accounts_in(&["news".to_string()]).unwrap();
+    assert!(!news.is_empty());
+    assert!(news.iter().all(|account| account.category == "news"));
+    assert_eq!(
+        config.accounts_in(&[]).unwrap().len(),
+        config.accounts.len()
+    );
+    assert!(matches!(
+        config.accounts_in(&["nonexistent".to_string()]),
+        Err(Error::Usage(_))
+    ));
+}
+
 #[tokio::test]
 async fn test_json_parse() {
-    let maybe_json = Config::get(&std::path::Path::new("conf/accounts.json"));
-    assert!(maybe_json.is_some());
+    let maybe_json = Config::get(std::path::Path::new("conf/accounts.json"));
+    assert!(maybe_json.is_ok());
     let json = maybe_json.unwrap();
     let test_category = "news".to_string();
     assert!(json.categories.contains(&test_category))
 }
+
+#[tokio::test]
+async fn test_deserialize_tweet_without_id() {
+    let json = r#"{"text":"hello","handle":"fakeHandle","date_utc":"2020-12-01T00:00:00Z","retweet_count":0,"favorite_count":0}"#;
+    let tweet: Tweet = serde_json::from_str(json).expect("Could not deserialize old tweet");
+    assert_eq!(tweet.id, 0);
+    assert_eq!(tweet.in_reply_to_status_id, None);
+    assert_eq!(tweet.lang, None);
+    assert!(tweet.hashtags.is_empty());
+    assert!(tweet.media_types.is_empty());
+}
+
+#[tokio::test]
+async fn test_serialize_tweet_skips_defaults() {
+    let tweet = crate::util::test::get_dummy_query_result().tweets.remove(0);
+    let json = serde_json::to_string(&tweet).expect("Could not serialize tweet");
+    assert!(!json.contains("quoted_status_id"));
+    assert!(json.contains("hashtags"));
+
+    let roundtrip: Tweet = serde_json::from_str(&json).expect("Could not deserialize tweet");
+    assert_eq!(roundtrip.hashtags, tweet.hashtags);
+    assert_eq!(roundtrip.lang, tweet.lang);
+}
+
+#[tokio::test]
+// Retweets stored before full texts were fetched
+async fn test_legacy_retweet_text() {
+    let tweet = crate::util::test::get_dummy_query_result().tweets.remove(0);
+    assert!(tweet.is_retweet());
+    assert_eq!(
+        tweet.full_text(),
+        "hello hello there are multiple words here, some repeated, hello"
+    );
+
+    let tweet = Tweet {
+        text: "hello there".to_string(),
+        retweeted_status_id: None,
+        ..tweet
+    };
+    assert!(!tweet.is_retweet());
+    assert_eq!(tweet.full_text(), "hello there");
+
+    // Full texts can end in "…" on their own
+    let tweet = Tweet {
+        text: "wait for it…".to_string(),
+        ..tweet
+    };
+    assert_eq!(tweet.full_text(), "wait for it…");
+    let tweet = Tweet {
+        is_retweet: true,
+        ..tweet
+    };
+    assert_eq!(tweet.full_text(), "wait for it…");
+}
+
+#[tokio::test]
+async fn test_consumer_keys_from_file() {
+    let test_dir = crate::util::test::get_test_dir("test_consumer_keys");
+    let keys_path = test_dir.join("consumer.json");
+    fs::write(&keys_path, r#"{"key": "fakeKey", "secret": "fakeSecret"}"#).unwrap();
+
+    let keys = auth::consumer_keys_from_file(&keys_path)
+        .expect("Could not read consumer keys")
+        .expect("Consumer keys should be there");
+    assert_eq!(keys.key, "fakeKey");
+    assert_eq!(keys.secret, "fakeSecret");
+    assert!(
+        auth::consumer_keys_from_file(&test_dir.join("missing.json"))
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[tokio::test]
+async fn test_access_token_roundtrip() {
+    let test_dir = crate::util::test::get_test_dir("test_access_token");
+    let access_path = test_dir.join("access.json");
+    let token = egg_mode::Token::Access {
+        consumer: egg_mode::KeyPair::new("fakeKey", "fakeSecret"),
+        access: egg_mode::KeyPair::new("fakeAccessKey", "fakeAccessSecret"),
+    };
+    auth::write_secret(&access_path, &serde_json::to_string_pretty(&token).unwrap()).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&access_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    assert!(matches!(
+        auth::get_access_token(&access_path),
+        Ok(egg_mode::Token::Access { .. })
+    ));
+
+    let bearer = egg_mode::Token::Bearer("fakeBearer".to_string());
+    fs::write(&access_path, serde_json::to_string(&bearer).unwrap()).unwrap();
+    assert!(matches!(
+        auth::get_access_token(&access_path),
+        Err(Error::Auth(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_effective_query() {
+    let json = r#"{"handle": "@twitter", "category": "tech", "result_type": "popular", "exclude_retweets": true, "query_template": "to:{handle}"}"#;
+    let account: TwitterAccount =
+        serde_json::from_str(json).expect("Could not deserialize account");
+    assert_eq!(account.search.result_type, Some(ResultType::Popular));
+    assert_eq!(
+        account.search.effective_query(&account.handle),
+        "to:twitter -filter:retweets"
+    );
+    assert_eq!(
+        SearchOptions::default().effective_query("@twitter"),
+        "@twitter"
+    );
+}
@@ -1,30 +1,104 @@
+use crate::error::Result;
 use std::path::Path;
 use walkdir::WalkDir;
 
-pub fn clear_directory(dir: &Path) -> Result<(), std::io::Error> {
+pub fn clear_directory(dir: &Path) -> Result<()> {
     // Get all the valid entries in the dir and delete them
-    WalkDir::new(&dir)
+    Ok(WalkDir::new(dir)
         .min_depth(1)
         .into_iter()
-        .filter_map(Result::ok)
-        .map(|entry| std::fs::remove_dir_all(entry.into_path()))
-        .collect()
+        .filter_map(std::result::Result::ok)
+        .try_for_each(|entry| std::fs::remove_dir_all(entry.into_path()))?)
 }
 
 #[cfg(test)]
 pub mod test {
 
-    use crate::analysis::{HandlePattern, SearchAnalysis};
-    use crate::twitter::{QueryResult, Tweet};
+    use crate::analysis::{AccountAge, HandlePattern, RetweetMode, SearchAnalysis, TimeWindow};
+    use crate::error::{Error, Result};
+    use crate::source::{MemorySource, SearchPage, SearchRequest, TweetSource};
+    use crate::storage::StorageHandler;
+    use crate::twitter::{Author, Config, QueryKind, QueryResult, Tweet};
+    use async_trait::async_trait;
+    use std::path::PathBuf;
     pub const TEST_TEMP_DIR: &str = "test_temp";
 
+    /// Empty directory "TEST_TEMP_DIR/test_name/" for a test's files
+    pub fn get_test_dir(unique_test_name: &str) -> PathBuf {
+        let test_dir: PathBuf = [TEST_TEMP_DIR, unique_test_name].iter().collect();
+        let _ = std::fs::remove_dir_all(&test_dir);
+        std::fs::create_dir_all(&test_dir).unwrap();
+        test_dir
+    }
+
+    /// Files storage in an empty test dir, see `get_test_dir`
+    pub fn get_test_storage(unique_test_name: &str) -> StorageHandler {
+        StorageHandler::new().storage_dir(&get_test_dir(unique_test_name))
+    }
+
+    /// Two categories, "news" fetched every 5 minutes, with an account each
+    pub fn get_test_config() -> Config {
+        serde_json::from_str(
+            r#"{"categories": ["news", "test"], "watch_intervals": {"news": 5}, "accounts": [
+                {"handle": "@BBCWorld", "category": "news"},
+                {"handle": "@twitter", "category": "test"}
+            ]}"#,
+        )
+        .unwrap()
+    }
+
+    /// A `MemorySource` that fails one query of one kind with `error`
+    pub struct FailingSource {
+        pub inner: MemorySource,
+        pub failing: (QueryKind, &'static str),
+        pub error: fn() -> Error,
+    }
+
+    #[async_trait]
+    impl TweetSource for FailingSource {
+        async fn search(&self, request: &SearchRequest) -> Result<SearchPage> {
+            if self.failing == (QueryKind::Search, request.query.as_str()) {
+                return Err((self.error)());
+            }
+            self.inner.search(request).await
+        }
+
+        async fn timeline(&self, request: &SearchRequest) -> Result<SearchPage> {
+            if self.failing == (QueryKind::Timeline, request.query.as_str()) {
+                return Err((self.error)());
+            }
+            self.inner.timeline(request).await
+        }
+    }
+
+    /// What Twitter answers with when it rejects the token itself
+    pub fn get_rejected_token_error() -> Error {
+        Error::from(egg_mode::error::Error::TwitterError(
+            hyper::HeaderMap::new(),
+            egg_mode::error::TwitterErrors {
+                errors: vec![egg_mode::error::TwitterErrorCode {
+                    message: "Invalid or expired token".to_string(),
+                    code: 89,
+                }],
+            },
+        ))
+    }
+
     /// Create a (mildly) valid SearchAnalysis that can be stored
     pub fn get_dummy_search_analysis() -> SearchAnalysis {
         SearchAnalysis {
             queries: vec!["dummy_search_analysis".to_string()],
+            category: None,
+            kind: QueryKind::Search,
             date_utc: chrono::Utc::now(),
             word_frequency: vec![("Hello".to_string(), 1)],
             handle_patterns: vec![(HandlePattern::Other, 1)],
+            account_ages: vec![(AccountAge::OverTenYears, 1)],
+            locations: vec![("Pittsburgh, PA".to_string(), 1)],
+            retweet_count: 0,
+            retweets: RetweetMode::Count,
+            window: TimeWindow::default(),
+            duplicate_count: 0,
         }
     }
 
@@ -32,15 +106,45 @@ pub mod test {
     pub fn get_dummy_query_result() -> QueryResult {
         QueryResult {
             query: "@twitter".to_string(),
+            kind: QueryKind::Search,
+            effective_query: Some("@twitter".to_string()),
             // Date doesn't actually matter for test content
             date_utc: chrono::Utc::now(),
             tweets: vec![Tweet {
+                id: 1337,
                 text: r#"RT @Twitter: hello hello there are multiple words here, some repeated, hello hello…"#.to_string(),
                 handle: "fakeHandle".to_string(),
                 date_utc: chrono::Utc::now(),
                 retweet_count: 47111,
                 favorite_count: 1234,
+                in_reply_to_status_id: None,
+                in_reply_to_user_id: None,
+                in_reply_to_handle: None,
+                retweeted_status_id: None,
+                quoted_status_id: None,
+                lang: Some("en".to_string()),
+                source: Some("Twitter Web App".to_string()),
+                hashtags: vec!["hello".to_string()],
+                mentions: vec!["Twitter".to_string()],
+                urls: Vec::new(),
+                media_types: Vec::new(),
+                // Stored before retweets were flagged, see the "RT @" prefix
+                is_retweet: false,
             }],
+            authors: vec![(
+                "fakeHandle".to_string(),
+                Author {
+                    name: "Fake Handle".to_string(),
+                    created_at: chrono::Utc::now() - chrono::Duration::days(3 * 365),
+                    location: Some("Pittsburgh, PA".to_string()),
+                    followers_count: 10,
+                    following_count: 20,
+                    verified: false,
+                    default_profile_image: true,
+                },
+            )]
+            .into_iter()
+            .collect(),
         }
     }
 }
@@ -0,0 +1,319 @@
+use crate::analysis::{run_analysis_on_categories, AnalysisConfig, TimeWindow};
+use crate::error::{Error, Result};
+use crate::source::TweetSource;
+use crate::storage::Storage;
+use crate::twitter::{run_query_from_config, Config};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::sync::mpsc;
+
+/// Minutes between fetches of a category if neither the CLI nor the configuration say otherwise
+pub const DEFAULT_WATCH_INTERVAL: u64 = 15;
+/// How often the accounts file is checked for changes while waiting
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct WatchOptions {
+    pub accounts_path: PathBuf,
+    /// Minutes, for categories without their own interval
+    pub default_interval: u64,
+    /// Only watch these categories, all of them if empty
+    pub categories: Vec<String>,
+    pub max_pages: Option<u32>,
+    pub concurrency: Option<usize>,
+    /// Analyze each category after fetching it
+    pub analysis: Option<AnalysisConfig>,
+}
+
+/// Time between two fetches of `category`
+fn interval_for(config: &Config, category: &str, default_interval: u64) -> Duration {
+    let minutes = config
+        .watch_intervals
+        .get(category)
+        .copied()
+        .unwrap_or(default_interval);
+    // An interval of 0 would keep hammering Twitter
+    Duration::from_secs(minutes.max(1) * 60)
+}
+
+/// Categories that have never been fetched or whose interval has passed
+fn due_categories(
+    categories: &[String],
+    next_fetch: &BTreeMap<String, Instant>,
+    now: Instant,
+) -> Vec<String> {
+    categories
+        .iter()
+        .filter(|category| next_fetch.get(*category).is_none_or(|due| *due <= now))
+        .cloned()
+        .collect()
+}
+
+/// Every category with at least one account, or the requested ones
+fn watched_categories(config: &Config, requested: &[String]) -> Vec<String> {
+    let mut categories: Vec<String> = config
+        .accounts
+        .iter()
+        .map(|account| account.category.clone())
+        .filter(|category| requested.is_empty() || requested.contains(category))
+        .collect();
+    categories.sort();
+    categories.dedup();
+    categories
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).ok()?.modified().ok()
+}
+
+/// Sends once per SIGINT or SIGTERM
+pub fn shutdown_signals() -> Result<mpsc::UnboundedReceiver<()>> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let ctrl_c_sender = sender.clone();
+    tokio::spawn(async move {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            if ctrl_c_sender.send(()).is_err() {
+                break;
+            }
+        }
+    });
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::spawn(async move {
+            while terminate.recv().await.is_some() {
+                if sender.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    Ok(receiver)
+}
+
+/// Failures that will happen again on every cycle, like a rejected token or a full disk.
+/// Network trouble, rate limits and categories without tweets yet can sort themselves out, an
+/// account Twitter refuses only fails its own query
+fn is_fatal(error: &Error) -> bool {
+    match error {
+        Error::Auth(_) | Error::Config(_) | Error::Storage(_) | Error::Usage(_) => true,
+        Error::Network(_)
+        | Error::RateLimit(_)
+        | Error::Parse(_)
+        | Error::NoData(_)
+        | Error::Refused(_) => false,
+    }
+}
+
+/// Fetch and optionally analyze the due categories. Errors that may go away are reported and
+/// watching goes on, fatal ones are returned
+async fn run_cycle(
+    source: &dyn TweetSource,
+    storage: &dyn Storage,
+    config: &Config,
+    categories: &[String],
+    options: &WatchOptions,
+) -> Result<()> {
+    println!("Fetching categories {:?}", categories);
+    if let Err(e) = run_query_from_config(
+        source,
+        storage,
+        config.clone(),
+        categories,
+        options.max_pages,
+        options.concurrency,
+        None,
+    )
+    .await
+    {
+        if is_fatal(&e) {
+            return Err(e);
+        }
+        eprintln!(
+            "Fetching {:?} failed, trying again next time: {}",
+            categories, e
+        );
+    }
+    if let Some(analysis) = &options.analysis {
+        for category in categories {
+            if let Err(e) = run_analysis_on_categories(
+                analysis.clone(),
+                config,
+                storage,
+                std::slice::from_ref(category),
+                crate::twitter::QueryKind::Search,
+                TimeWindow::default(),
+            )
+            .await
+            {
+                if is_fatal(&e) {
+                    return Err(e);
+                }
+                eprintln!("Analyzing {:?} failed: {}", category, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keep fetching the configured accounts until SIGINT or SIGTERM, or until a fatal error.
+/// The first signal lets the running cycle finish, a second one stops right away. Saves never
+/// wait on anything, so even then no file is left half written.
+pub async fn run_watch(
+    source: &dyn TweetSource,
+    storage: &dyn Storage,
+    options: WatchOptions,
+) -> Result<()> {
+    let mut shutdown = shutdown_signals()?;
+    let mut config = Config::get(&options.accounts_path)?;
+    let mut config_modified = modified(&options.accounts_path);
+    let mut next_fetch: BTreeMap<String, Instant> = BTreeMap::new();
+    if watched_categories(&config, &options.categories).is_empty() {
+        return Err(Error::NoData(format!(
+            "No accounts to watch in {:?}",
+            options.accounts_path
+        )));
+    }
+
+    loop {
+        // Pick up edits to the accounts file, a broken file keeps the last good one around
+        let current_modified = modified(&options.accounts_path);
+        if current_modified != config_modified {
+            config_modified = current_modified;
+            match Config::get(&options.accounts_path) {
+                Ok(reloaded) => {
+                    println!("Reloaded {:?}", options.accounts_path);
+                    config = reloaded;
+                }
+                Err(e) => eprintln!("Keeping the previous accounts, {}", e),
+            }
+        }
+
+        let categories = watched_categories(&config, &options.categories);
+        let due = due_categories(&categories, &next_fetch, Instant::now());
+        if !due.is_empty() {
+            let finished = {
+                let cycle = run_cycle(source, storage, &config, &due, &options);
+                tokio::pin!(cycle);
+                tokio::select! {
+                    result = &mut cycle => Some(result),
+                    _ = shutdown.recv() => {
+                        println!("Finishing the current cycle, stop again to quit right away");
+                        tokio::select! {
+                            result = &mut cycle => result?,
+                            _ = shutdown.recv() => {}
+                        }
+                        None
+                    }
+                }
+            };
+            match finished {
+                Some(result) => result?,
+                None => return Ok(()),
+            }
+            let now = Instant::now();
+            for category in due {
+                let interval = interval_for(&config, &category, options.default_interval);
+                next_fetch.insert(category, now + interval);
+            }
+        }
+
+        let until_next = categories
+            .iter()
+            .filter_map(|category| next_fetch.get(category))
+            .min()
+            .map_or(Duration::from_secs(0), |due| {
+                due.saturating_duration_since(Instant::now())
+            });
+        tokio::select! {
+            _ = tokio::time::delay_for(until_next.min(RELOAD_CHECK_INTERVAL)) => {}
+            _ = shutdown.recv() => {
+                println!("Stopped watching");
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{due_categories, interval_for, run_cycle, watched_categories, WatchOptions};
+    use crate::error::Error;
+    use crate::source::MemorySource;
+    use crate::storage::MemoryStorage;
+    use crate::twitter::QueryKind;
+    use crate::util::test::{get_test_config, FailingSource};
+    use std::{
+        collections::BTreeMap,
+        path::PathBuf,
+        time::{Duration, Instant},
+    };
+
+    #[tokio::test]
+    async fn test_interval_for() {
+        let config = get_test_config();
+        assert_eq!(
+            interval_for(&config, "news", 15),
+            Duration::from_secs(5 * 60)
+        );
+        assert_eq!(
+            interval_for(&config, "test", 15),
+            Duration::from_secs(15 * 60)
+        );
+        assert_eq!(interval_for(&config, "test", 0), Duration::from_secs(60));
+    }
+
+    #[tokio::test]
+    async fn test_due_categories() {
+        let config = get_test_config();
+        let categories = watched_categories(&config, &[]);
+        assert_eq!(categories, vec!["news".to_string(), "test".to_string()]);
+
+        let now = Instant::now();
+        let mut next_fetch = BTreeMap::new();
+        // Everything is due on the first cycle
+        assert_eq!(due_categories(&categories, &next_fetch, now), categories);
+
+        next_fetch.insert("news".to_string(), now + Duration::from_secs(60));
+        next_fetch.insert("test".to_string(), now);
+        assert_eq!(
+            due_categories(&categories, &next_fetch, now),
+            vec!["test".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    // A rejected token ends watching, a network error is tried again next cycle
+    async fn test_cycle_errors() {
+        let config = get_test_config();
+        let categories = watched_categories(&config, &[]);
+        let options = WatchOptions {
+            accounts_path: PathBuf::from("accounts.json"),
+            default_interval: 15,
+            categories: Vec::new(),
+            max_pages: None,
+            concurrency: None,
+            analysis: None,
+        };
+        let storage = MemoryStorage::new();
+
+        let rejected = FailingSource {
+            inner: MemorySource::new(),
+            failing: (QueryKind::Search, "@twitter"),
+            error: || Error::Auth("Twitter responded with 401 Unauthorized".to_string()),
+        };
+        let result = run_cycle(&rejected, &storage, &config, &categories, &options).await;
+        assert!(matches!(result, Err(Error::Auth(_))));
+
+        let offline = FailingSource {
+            inner: MemorySource::new(),
+            failing: (QueryKind::Search, "@twitter"),
+            error: || Error::Network(egg_mode::error::Error::BadUrl),
+        };
+        let result = run_cycle(&offline, &storage, &config, &categories, &options).await;
+        assert!(result.is_ok());
+    }
+}